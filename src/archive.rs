@@ -0,0 +1,242 @@
+use crate::{store, RETENTION};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+// number of archive entries scanned, or evicted, between two yields to the requests
+const GC_BATCH: usize = 64;
+
+// number of leases held on each archive entry
+static LEASES: OnceCell<Mutex<HashMap<String, usize>>> = OnceCell::new();
+
+/// Retention policy of the working copy of the file store.
+///
+/// The garbage collector evicts the oldest entries first, until none is older than `max_age`
+/// and the entries fit in `max_bytes` and `max_files`. `None` means no limit. With a remote
+/// store, the policy only applies to the working copy, and the evicted files are fetched again
+/// from the store when requested.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct Retention {
+    /// Maximum age of an entry, in seconds.
+    pub(crate) max_age: Option<u64>,
+    /// Maximum total size of the entries, in bytes.
+    pub(crate) max_bytes: Option<u64>,
+    /// Maximum number of entries.
+    pub(crate) max_files: Option<u64>,
+    /// Seconds between two collections.
+    pub(crate) gc_interval: u64,
+    /// Delete the audio of a transcription or translation request once it is answered.
+    pub(crate) ephemeral_uploads: bool,
+    /// Seconds a finished transcription job is kept before it is forgotten.
+    pub(crate) job_ttl: u64,
+}
+impl Retention {
+    /// Whether any limit is set, i.e. whether the garbage collector has anything to do.
+    pub(crate) fn is_limited(&self) -> bool {
+        self.max_age.is_some() || self.max_bytes.is_some() || self.max_files.is_some()
+    }
+}
+
+/// Keeps an archive entry from being collected while a request uses it.
+///
+/// An ephemeral lease also deletes the entry once the last lease on it is dropped.
+#[derive(Debug)]
+pub(crate) struct ArchiveLease {
+    file_id: String,
+    ephemeral: bool,
+}
+impl ArchiveLease {
+    /// Lease the entry of the given file. `uploaded` tells that the entry was created by the
+    /// request itself, in which case it is deleted at the end of the request in the
+    /// `--ephemeral-uploads` mode.
+    pub(crate) fn new(file_id: impl Into<String>, uploaded: bool) -> Self {
+        let file_id = file_id.into();
+        *leases().lock().unwrap().entry(file_id.clone()).or_default() += 1;
+
+        let ephemeral_uploads = RETENTION
+            .get()
+            .map(|retention| retention.ephemeral_uploads)
+            .unwrap_or(false);
+
+        Self {
+            file_id,
+            ephemeral: uploaded && ephemeral_uploads,
+        }
+    }
+}
+impl Drop for ArchiveLease {
+    fn drop(&mut self) {
+        let released = {
+            let mut leases = leases().lock().unwrap();
+            match leases.get_mut(&self.file_id) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                _ => {
+                    leases.remove(&self.file_id);
+                    true
+                }
+            }
+        };
+
+        if released && self.ephemeral && !self.file_id.is_empty() {
+            let dir = store::working_dir(&self.file_id);
+            match fs::remove_dir_all(&dir) {
+                Ok(_) => {
+                    info!(target: "stdout", "Deleted the ephemeral upload {}", &self.file_id);
+
+                    crate::index::remove(&self.file_id);
+                    store::remove_later(&self.file_id);
+                }
+                Err(e) => {
+                    error!(target: "stdout", "Failed to delete the ephemeral upload {}. {}", &self.file_id, e)
+                }
+            }
+        }
+    }
+}
+
+/// Enforce the retention policy every `gc_interval` seconds, forget the transcription jobs
+/// finished for more than `job_ttl` seconds, and delete the expired uploads.
+pub(crate) async fn run_gc(retention: Retention) {
+    let mut interval = tokio::time::interval(Duration::from_secs(retention.gc_interval.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        if retention.is_limited() {
+            if let Err(e) = collect(&retention).await {
+                error!(target: "stdout", "Failed to collect the archives. {}", e);
+            }
+        }
+
+        crate::backend::jobs::evict_finished(retention.job_ttl);
+        crate::backend::uploads::evict_expired();
+    }
+}
+
+// an entry of the archive directory
+struct Entry {
+    file_id: String,
+    path: PathBuf,
+    created_at: SystemTime,
+    bytes: u64,
+}
+
+/// Evict the oldest entries of the working copy until it complies with the retention policy.
+/// Entries leased by running requests are skipped.
+///
+/// The file store is never touched: with a remote store, the record of an evicted file is kept
+/// and the file stays available from the store; otherwise the record is dropped with the file.
+///
+/// The server runs on a single thread, so the walk yields to the requests after every
+/// `GC_BATCH` entries instead of holding the thread for the whole archive tree.
+pub(crate) async fn collect(retention: &Retention) -> Result<(), String> {
+    let root = store::working_root();
+    if !root.is_dir() {
+        return Ok(());
+    }
+
+    let mut entries = Vec::new();
+    for (i, dir) in fs::read_dir(root)
+        .map_err(|e| format!("Failed to read the archive directory. {}", e))?
+        .flatten()
+        .enumerate()
+    {
+        if i % GC_BATCH == GC_BATCH - 1 {
+            tokio::task::yield_now().await;
+        }
+
+        let path = dir.path();
+        if !path.is_dir() {
+            continue;
+        }
+
+        // an entry is as old as its oldest file, i.e. the stored upload
+        let mut created_at = dir
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut bytes = 0;
+        if let Ok(files) = fs::read_dir(&path) {
+            for metadata in files.flatten().filter_map(|file| file.metadata().ok()) {
+                bytes += metadata.len();
+                if let Ok(modified) = metadata.modified() {
+                    created_at = created_at.min(modified);
+                }
+            }
+        }
+
+        entries.push(Entry {
+            file_id: dir.file_name().to_string_lossy().to_string(),
+            path,
+            created_at,
+            bytes,
+        });
+    }
+    entries.sort_by_key(|entry| entry.created_at);
+
+    let now = SystemTime::now();
+    let mut count = entries.len() as u64;
+    let mut total_bytes: u64 = entries.iter().map(|entry| entry.bytes).sum();
+    let mut evicted = 0;
+    for entry in entries {
+        let age = now
+            .duration_since(entry.created_at)
+            .unwrap_or_default()
+            .as_secs();
+        let expired = retention.max_age.is_some_and(|max_age| age > max_age);
+        let too_many = retention
+            .max_files
+            .is_some_and(|max_files| count > max_files);
+        let too_large = retention
+            .max_bytes
+            .is_some_and(|max_bytes| total_bytes > max_bytes);
+        if !(expired || too_many || too_large) {
+            continue;
+        }
+
+        evicted += 1;
+        if evicted % GC_BATCH == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // the lease is checked after the yield, as a request may have taken the entry meanwhile
+        if leases().lock().unwrap().contains_key(&entry.file_id) {
+            continue;
+        }
+
+        match fs::remove_dir_all(&entry.path) {
+            Ok(_) => {
+                info!(target: "stdout", "Evicted the archive entry {} ({} bytes, {}s old)", &entry.file_id, entry.bytes, age);
+                if !store::is_remote() {
+                    crate::index::remove(&entry.file_id);
+                }
+
+                count -= 1;
+                total_bytes -= entry.bytes;
+            }
+            // deleted meanwhile, e.g. by `DELETE /v1/files/{file_id}`
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                count -= 1;
+                total_bytes -= entry.bytes;
+            }
+            Err(e) => {
+                error!(target: "stdout", "Failed to evict the archive entry {}. {}", &entry.file_id, e)
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn leases() -> &'static Mutex<HashMap<String, usize>> {
+    LEASES.get_or_init(|| Mutex::new(HashMap::new()))
+}
@@ -0,0 +1,260 @@
+use crate::error::ServerError;
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use llama_core::metadata::whisper::WHISPER_SAMPLE_RATE;
+use std::{io::Cursor, path::Path};
+
+/// Decoded wav audio. The samples are interleaved and normalized to `[-1.0, 1.0]`.
+#[derive(Debug, Clone)]
+pub(crate) struct WavAudio {
+    pub(crate) spec: WavSpec,
+    pub(crate) samples: Vec<f32>,
+}
+impl WavAudio {
+    /// Create mono 16-bit audio from normalized samples.
+    pub(crate) fn from_samples(samples: Vec<f32>, sample_rate: u32) -> Self {
+        Self {
+            spec: WavSpec {
+                channels: 1,
+                sample_rate,
+                bits_per_sample: 16,
+                sample_format: SampleFormat::Int,
+            },
+            samples,
+        }
+    }
+
+    /// Create mono audio from little-endian 16-bit PCM bytes. A trailing odd byte is ignored.
+    pub(crate) fn from_pcm16(bytes: &[u8], sample_rate: u32) -> Self {
+        let samples = bytes
+            .chunks_exact(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]) as f32 / 32768.0)
+            .collect();
+
+        Self::from_samples(samples, sample_rate)
+    }
+
+    /// Create mono audio from G.711 μ-law bytes.
+    pub(crate) fn from_g711_ulaw(bytes: &[u8], sample_rate: u32) -> Self {
+        let samples = bytes
+            .iter()
+            .map(|byte| {
+                let byte = !byte;
+                let exponent = (byte >> 4) & 0x07;
+                let mantissa = (byte & 0x0f) as i32;
+                let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+                let sample = if byte & 0x80 != 0 {
+                    -magnitude
+                } else {
+                    magnitude
+                };
+
+                sample as f32 / 32768.0
+            })
+            .collect();
+
+        Self::from_samples(samples, sample_rate)
+    }
+
+    /// Create mono audio from G.711 A-law bytes.
+    pub(crate) fn from_g711_alaw(bytes: &[u8], sample_rate: u32) -> Self {
+        let samples = bytes
+            .iter()
+            .map(|byte| {
+                let byte = byte ^ 0x55;
+                let exponent = (byte >> 4) & 0x07;
+                let mantissa = (byte & 0x0f) as i32;
+                let magnitude = match exponent {
+                    0 => (mantissa << 4) + 8,
+                    _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+                };
+                let sample = if byte & 0x80 != 0 {
+                    magnitude
+                } else {
+                    -magnitude
+                };
+
+                sample as f32 / 32768.0
+            })
+            .collect();
+
+        Self::from_samples(samples, sample_rate)
+    }
+
+    /// Decode the wav file at the given path.
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        Self::open_range(path, 0.0, f64::MAX)
+    }
+
+    /// Decode the audio between `start` and `end`, both in seconds, of the wav file at the given
+    /// path, without decoding the rest of the file.
+    pub(crate) fn open_range(
+        path: impl AsRef<Path>,
+        start: f64,
+        end: f64,
+    ) -> Result<Self, ServerError> {
+        let to_err = |e: hound::Error| {
+            ServerError::Operation(format!(
+                "Failed to read the wav file {}. {}",
+                path.as_ref().display(),
+                e
+            ))
+        };
+
+        let mut reader = WavReader::open(path.as_ref()).map_err(to_err)?;
+        let spec = reader.spec();
+
+        let to_frame = |seconds: f64| {
+            ((seconds.max(0.0) * spec.sample_rate as f64).min(reader.duration() as f64)) as u32
+        };
+        let start = to_frame(start);
+        let end = to_frame(end).max(start);
+        reader
+            .seek(start)
+            .map_err(|e| to_err(hound::Error::IoError(e)))?;
+        let count = (end - start) as usize * spec.channels.max(1) as usize;
+
+        let samples: Result<Vec<f32>, hound::Error> = match spec.sample_format {
+            SampleFormat::Float => reader.samples::<f32>().take(count).collect(),
+            SampleFormat::Int => {
+                let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .take(count)
+                    .map(|sample| sample.map(|sample| sample as f32 / scale))
+                    .collect()
+            }
+        };
+
+        Ok(Self {
+            spec,
+            samples: samples.map_err(to_err)?,
+        })
+    }
+
+    /// Number of samples per channel.
+    pub(crate) fn frames(&self) -> usize {
+        self.samples.len() / self.spec.channels.max(1) as usize
+    }
+
+    /// Duration in seconds.
+    pub(crate) fn duration(&self) -> f64 {
+        self.frames() as f64 / self.spec.sample_rate as f64
+    }
+
+    /// Copy the audio between `start` and `end`, both in seconds.
+    pub(crate) fn slice(&self, start: f64, end: f64) -> WavAudio {
+        let channels = self.spec.channels.max(1) as usize;
+        let to_frame = |seconds: f64| {
+            ((seconds.max(0.0) * self.spec.sample_rate as f64) as usize).min(self.frames())
+        };
+        let start = to_frame(start);
+        let end = to_frame(end).max(start);
+
+        WavAudio {
+            spec: self.spec,
+            samples: self.samples[start * channels..end * channels].to_vec(),
+        }
+    }
+
+    /// Encode the audio as a wav file at the given path, with the sample format it was decoded from.
+    pub(crate) fn save(&self, path: impl AsRef<Path>) -> Result<(), ServerError> {
+        let writer = WavWriter::create(path.as_ref(), self.spec).map_err(|e| {
+            ServerError::Operation(format!(
+                "Failed to create the wav file {}. {}",
+                path.as_ref().display(),
+                e
+            ))
+        })?;
+
+        self.write(writer)
+    }
+
+    /// Encode the audio as wav bytes, with the sample format it was decoded from.
+    pub(crate) fn to_wav_bytes(&self) -> Result<Vec<u8>, ServerError> {
+        let mut cursor = Cursor::new(Vec::new());
+        let writer = WavWriter::new(&mut cursor, self.spec).map_err(|e| {
+            ServerError::Operation(format!("Failed to create the wav encoder. {}", e))
+        })?;
+        self.write(writer)?;
+
+        Ok(cursor.into_inner())
+    }
+
+    /// Save the audio as a mono 16 kHz wav file for whisper, converting it if needed.
+    pub(crate) fn save_for_whisper(&self, path: impl AsRef<Path>) -> Result<(), ServerError> {
+        if self.spec.sample_rate == WHISPER_SAMPLE_RATE as u32 && self.spec.channels == 1 {
+            return self.save(path);
+        }
+
+        // create a audio converter
+        let converter = wavup::AudioConverterBuilder::new(
+            path.as_ref().to_string_lossy(),
+            WHISPER_SAMPLE_RATE as u32,
+        )
+        .build();
+
+        converter
+            .convert_audio_from_bytes(&self.to_wav_bytes()?)
+            .map_err(|e| ServerError::Operation(format!("Failed to convert audio. {}", e)))
+    }
+
+    // write the samples with the given encoder
+    fn write<W: std::io::Write + std::io::Seek>(
+        &self,
+        mut writer: WavWriter<W>,
+    ) -> Result<(), ServerError> {
+        let to_err = |e: hound::Error| {
+            ServerError::Operation(format!("Failed to write the wav data. {}", e))
+        };
+
+        match self.spec.sample_format {
+            SampleFormat::Float => {
+                for sample in self.samples.iter() {
+                    writer.write_sample(*sample).map_err(to_err)?;
+                }
+            }
+            SampleFormat::Int => {
+                let max = ((1_i64 << (self.spec.bits_per_sample - 1)) - 1) as f32;
+                for sample in self.samples.iter() {
+                    let sample = (sample.clamp(-1.0, 1.0) * max).round() as i32;
+                    writer.write_sample(sample).map_err(to_err)?;
+                }
+            }
+        }
+
+        writer.finalize().map_err(to_err)
+    }
+}
+
+/// Whether the file at the given path is a mono 16 kHz 16-bit PCM wav file, which whisper reads
+/// as is.
+pub(crate) fn is_whisper_wav(path: impl AsRef<Path>) -> bool {
+    match WavReader::open(path) {
+        Ok(reader) => {
+            let spec = reader.spec();
+            spec.sample_rate == WHISPER_SAMPLE_RATE as u32
+                && spec.channels == 1
+                && spec.bits_per_sample == 16
+                && spec.sample_format == SampleFormat::Int
+        }
+        Err(_) => false,
+    }
+}
+
+/// Duration of a wav file in seconds, or `None` if the file is not a readable wav file.
+pub(crate) fn wav_duration(path: impl AsRef<Path>) -> Option<f64> {
+    let reader = WavReader::open(path).ok()?;
+    let sample_rate = reader.spec().sample_rate;
+    if sample_rate == 0 {
+        return None;
+    }
+
+    Some(reader.duration() as f64 / sample_rate as f64)
+}
+
+/// Sample rate of a wav file, or `None` if the file is not a readable wav file.
+pub(crate) fn wav_sample_rate(path: impl AsRef<Path>) -> Option<u32> {
+    WavReader::open(path)
+        .ok()
+        .map(|reader| reader.spec().sample_rate)
+}
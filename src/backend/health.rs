@@ -0,0 +1,280 @@
+use super::{
+    inference::AudioRequest,
+    scheduler::{self, Ticket},
+};
+use crate::{
+    archive::ArchiveLease,
+    error::{self, ServerError},
+    shutdown, store, transcript,
+    utils::{json_response, now},
+    LLAMA_API_KEY,
+};
+use endpoints::{audio::transcription::TranscriptionRequest, files::FileObject};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::{fs, sync::Mutex, time::Instant};
+
+// status of the warmup, which tells whether the server is ready to run inference
+static WARMUP: OnceCell<Mutex<WarmupStatus>> = OnceCell::new();
+
+// short clip transcribed by the self-test, and its expected transcript
+const SAMPLE: &[u8] = include_bytes!("../../data/test.wav");
+const SAMPLE_FILENAME: &str = "selftest.wav";
+const EXPECTED: &str = "This is a test record for Whisper.cpp";
+
+/// Status of the warmup inference run at startup with `--warmup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WarmupStatus {
+    Disabled,
+    Pending,
+    Passed,
+    Failed,
+}
+
+/// Result of a self-test, as returned by `/v1/selftest`.
+#[derive(Debug, Serialize)]
+pub(crate) struct SelfTest {
+    pub(crate) passed: bool,
+    pub(crate) expected: String,
+    pub(crate) transcript: Option<String>,
+    /// Seconds taken by the test, including the wait for the whisper context.
+    pub(crate) elapsed: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Set whether a warmup inference is run at startup. Called once at startup.
+pub(crate) fn init(warmup: bool) -> Result<(), ServerError> {
+    let warmup = match warmup {
+        true => WarmupStatus::Pending,
+        false => WarmupStatus::Disabled,
+    };
+
+    WARMUP
+        .set(Mutex::new(warmup))
+        .map_err(|_| ServerError::Operation("Failed to set `WARMUP`.".to_string()))
+}
+
+/// Run the self-test once, and report the server as ready if it passes.
+pub(crate) async fn warmup() {
+    info!(target: "stdout", "Warm up the whisper context with the self-test");
+
+    let test = match scheduler::enqueue() {
+        Ok(ticket) => selftest(ticket).await.ok(),
+        Err(_) => None,
+    };
+    let status = match test {
+        Some(test) if test.passed => {
+            info!(target: "stdout", "Warmup passed in {:.3}s", test.elapsed);
+
+            WarmupStatus::Passed
+        }
+        Some(test) => {
+            error!(target: "stdout", "Warmup failed: {}", test.error.as_deref().unwrap_or_default());
+
+            WarmupStatus::Failed
+        }
+        None => {
+            error!(target: "stdout", "Warmup failed: no whisper context available");
+
+            WarmupStatus::Failed
+        }
+    };
+
+    *warmup_status().lock().unwrap() = status;
+}
+
+/// Handle `GET /health`: a 200 as long as the server answers requests.
+pub(crate) async fn health_handler() -> Response<Body> {
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({ "status": "ok" }).to_string(),
+    )
+}
+
+/// Handle `GET /ready`: a 200 once the warmup, if any, has passed, and a 503 before that or once
+/// the server is shutting down.
+///
+/// The whisper context is created before the server listens, so a server which answers has its
+/// model loaded; only the warmup tells whether inference works.
+pub(crate) async fn ready_handler() -> Response<Body> {
+    let warmup = *warmup_status().lock().unwrap();
+    let draining = shutdown::is_draining();
+
+    let ready = matches!(warmup, WarmupStatus::Disabled | WarmupStatus::Passed) && !draining;
+    let (status, code) = match (ready, draining) {
+        (true, _) => ("ready", StatusCode::OK),
+        (false, true) => ("draining", StatusCode::SERVICE_UNAVAILABLE),
+        (false, false) => ("not_ready", StatusCode::SERVICE_UNAVAILABLE),
+    };
+
+    let body = serde_json::json!({
+        "status": status,
+        "warmup": warmup,
+    });
+
+    json_response(code, body.to_string())
+}
+
+/// Handle `GET /v1/selftest`: transcribe a short bundled clip and compare the transcript with
+/// the expected one. A 200 if it matches, a 503 otherwise. The test takes a place in the
+/// inference queue like any transcription, and is refused with a 429 when the queue is full.
+///
+/// As the test runs an inference, it requires the API key, if any, even without the
+/// `authorization` header, which the other endpoints let through.
+pub(crate) async fn selftest_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming self-test request");
+
+    if req.method() != Method::GET {
+        let err_msg = "Invalid HTTP Method.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    if let Some(stored_api_key) = LLAMA_API_KEY.get() {
+        let api_key = req
+            .headers()
+            .get("authorization")
+            .and_then(|auth_header| auth_header.to_str().ok())
+            .and_then(|auth_header| auth_header.split(' ').nth(1))
+            .unwrap_or_default();
+        if api_key != stored_api_key {
+            let err_msg = "The self-test requires the API key.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::unauthorized(err_msg);
+        }
+    }
+
+    // take a place in the inference queue
+    let ticket = match scheduler::enqueue() {
+        Ok(ticket) => ticket,
+        Err(response) => return response,
+    };
+
+    let test = match selftest(ticket).await {
+        Ok(test) => test,
+        Err(response) => return response,
+    };
+    let code = match test.passed {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    let res = match serde_json::to_string(&test) {
+        Ok(body) => json_response(code, body),
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the self-test. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    };
+
+    info!(target: "stdout", "Send the self-test response");
+
+    res
+}
+
+/// Transcribe the bundled clip and compare the transcript with the expected one, ignoring case
+/// and punctuation.
+///
+/// The test waits for the whisper context with the given place in the inference queue, and
+/// returns the response of the queue if the wait times out.
+#[allow(clippy::result_large_err)]
+pub(crate) async fn selftest(mut ticket: Ticket) -> Result<SelfTest, Response<Body>> {
+    let started_at = Instant::now();
+
+    ticket.acquire().await?;
+    let (transcript, error) = match run_sample(ticket).await {
+        Ok(transcript) => (Some(transcript), None),
+        Err(e) => (None, Some(e)),
+    };
+    let passed = transcript
+        .as_deref()
+        .is_some_and(|transcript| matches_expected(transcript, EXPECTED));
+    let error = match (passed, error, &transcript) {
+        (false, None, Some(transcript)) => Some(format!(
+            "The transcript does not match the expected one: {}",
+            transcript
+        )),
+        (_, error, _) => error,
+    };
+
+    Ok(SelfTest {
+        passed,
+        expected: EXPECTED.to_string(),
+        transcript,
+        elapsed: started_at.elapsed().as_secs_f64(),
+        error,
+    })
+}
+
+// transcribe the bundled clip in an archive directory of its own, on the whisper context held by
+// the ticket
+async fn run_sample(ticket: Ticket) -> Result<String, String> {
+    let file_id = format!("selftest_{}", uuid::Uuid::new_v4());
+    let _lease = ArchiveLease::new(&file_id, false);
+
+    let dir = store::working_dir(&file_id);
+    fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(dir.join(SAMPLE_FILENAME), SAMPLE))
+        .map_err(|e| format!("Failed to write the self-test audio. {}", e))?;
+
+    let created_at = now();
+    let request = AudioRequest::Transcription(TranscriptionRequest {
+        file: FileObject {
+            id: file_id.clone(),
+            bytes: SAMPLE.len() as u64,
+            created_at,
+            filename: SAMPLE_FILENAME.to_string(),
+            object: "file".to_string(),
+            purpose: "assistants".to_string(),
+        },
+        language: Some("en".to_string()),
+        ..Default::default()
+    });
+
+    let output = request.run().await;
+    drop(ticket);
+    let _ = fs::remove_dir_all(&dir);
+
+    Ok(transcript::plain_text(&transcript::parse_segments(output?)))
+}
+
+// whether the words of `expected` appear in order in `transcript`
+fn matches_expected(transcript: &str, expected: &str) -> bool {
+    let words = |text: &str| {
+        text.split_whitespace()
+            .map(|word| {
+                word.chars()
+                    .filter(|c| c.is_alphanumeric())
+                    .flat_map(|c| c.to_lowercase())
+                    .collect::<String>()
+            })
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<String>>()
+    };
+
+    let transcript = words(transcript);
+    let expected = words(expected);
+
+    !expected.is_empty()
+        && transcript
+            .windows(expected.len())
+            .any(|window| window == expected.as_slice())
+}
+
+fn warmup_status() -> &'static Mutex<WarmupStatus> {
+    WARMUP.get_or_init(|| Mutex::new(WarmupStatus::Disabled))
+}
@@ -0,0 +1,290 @@
+use crate::{
+    audio::{self, WavAudio},
+    store,
+    transcript::{self, Segment},
+    AUDIO_CHUNKING,
+};
+use endpoints::{
+    audio::{transcription::TranscriptionRequest, translation::TranslationRequest},
+    files::FileObject,
+};
+use std::{fs, path::Path};
+
+/// Split long audio into windows which are transcribed separately.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Chunking {
+    /// Length of the windows, in seconds.
+    pub(crate) length: f64,
+    /// Overlap between consecutive windows, in seconds.
+    pub(crate) overlap: f64,
+}
+impl Chunking {
+    /// Create the chunking settings, or `None` if chunking is disabled, i.e. `length` is `0`.
+    pub(crate) fn new(length: f64, overlap: f64) -> Result<Option<Self>, String> {
+        if length == 0.0 {
+            return Ok(None);
+        }
+
+        if !(length > 0.0 && length.is_finite()) {
+            return Err(format!(
+                "Invalid chunk length: {}. It should be a positive number of seconds.",
+                length
+            ));
+        }
+        if !(overlap >= 0.0 && overlap < length / 2.0) {
+            return Err(format!(
+                "Invalid chunk overlap: {}. It should be at least 0 and less than half of the chunk length.",
+                overlap
+            ));
+        }
+
+        Ok(Some(Self { length, overlap }))
+    }
+
+    /// Merge the `chunk_length` and `chunk_overlap` fields of a request with the defaults set
+    /// from the command line.
+    pub(crate) fn for_request(
+        length: Option<f64>,
+        overlap: Option<f64>,
+    ) -> Result<Option<Self>, String> {
+        let defaults = AUDIO_CHUNKING.get().copied().flatten();
+
+        let length = match (length, defaults) {
+            (Some(length), _) => length,
+            (None, Some(defaults)) => defaults.length,
+            (None, None) => 0.0,
+        };
+        let overlap = match (overlap, defaults) {
+            (Some(overlap), _) => overlap,
+            (None, Some(defaults)) => defaults.overlap,
+            (None, None) => 0.0,
+        };
+
+        Self::new(length, overlap)
+    }
+}
+
+/// A transcription or translation request to run on the whisper context.
+pub(crate) enum AudioRequest {
+    Transcription(TranscriptionRequest),
+    Translation(TranslationRequest),
+}
+impl AudioRequest {
+    /// The audio file the request targets.
+    pub(crate) fn file(&self) -> &FileObject {
+        match self {
+            AudioRequest::Transcription(request) => &request.file,
+            AudioRequest::Translation(request) => &request.file,
+        }
+    }
+
+    /// The range of the original audio the request targets, in seconds, from its `offset_time`
+    /// and `duration` in milliseconds. The end is `None` for the rest of the audio.
+    pub(crate) fn time_range(&self) -> (f64, Option<f64>) {
+        let (offset_time, duration) = match self {
+            AudioRequest::Transcription(request) => (request.offset_time, request.duration),
+            AudioRequest::Translation(request) => (request.offset_time, request.duration),
+        };
+
+        let start = offset_time.unwrap_or(0) as f64 / 1000.0;
+        // whisper reads a zero duration as the rest of the audio
+        let end = duration
+            .filter(|duration| *duration > 0)
+            .map(|duration| start + duration as f64 / 1000.0);
+
+        (start, end)
+    }
+
+    /// Copy the request, targeting another file of the same archive directory.
+    ///
+    /// The other file, a window or the speech of the audio, has a timeline of its own, so
+    /// `offset_time` and `duration` are cleared: the caller cuts the file to the requested range.
+    pub(crate) fn with_filename(&self, filename: impl Into<String>) -> Self {
+        let mut request = self.copy();
+        match &mut request {
+            AudioRequest::Transcription(request) => {
+                request.file.filename = filename.into();
+                request.offset_time = None;
+                request.duration = None;
+            }
+            AudioRequest::Translation(request) => {
+                request.file.filename = filename.into();
+                request.offset_time = None;
+                request.duration = None;
+            }
+        }
+
+        request
+    }
+
+    /// Copy the request for a word-level run, in which whisper emits one word per segment.
+    pub(crate) fn word_level(&self) -> Self {
+        let mut request = self.copy();
+        match &mut request {
+            AudioRequest::Transcription(request) => {
+                request.max_len = Some(1);
+                request.split_on_word = Some(true);
+            }
+            AudioRequest::Translation(request) => {
+                request.max_len = Some(1);
+                request.split_on_word = Some(true);
+            }
+        }
+
+        request
+    }
+
+    // copy the request field by field
+    #[allow(clippy::needless_update)]
+    fn copy(&self) -> Self {
+        let file = copy_file_object(self.file());
+
+        match self {
+            AudioRequest::Transcription(request) => {
+                AudioRequest::Transcription(TranscriptionRequest {
+                    file,
+                    model: request.model.clone(),
+                    language: request.language.clone(),
+                    prompt: request.prompt.clone(),
+                    response_format: request.response_format.clone(),
+                    temperature: request.temperature,
+                    detect_language: request.detect_language,
+                    offset_time: request.offset_time,
+                    duration: request.duration,
+                    max_context: request.max_context,
+                    max_len: request.max_len,
+                    split_on_word: request.split_on_word,
+                    ..Default::default()
+                })
+            }
+            AudioRequest::Translation(request) => AudioRequest::Translation(TranslationRequest {
+                file,
+                model: request.model.clone(),
+                language: request.language.clone(),
+                prompt: request.prompt.clone(),
+                response_format: request.response_format.clone(),
+                temperature: request.temperature,
+                detect_language: request.detect_language,
+                offset_time: request.offset_time,
+                duration: request.duration,
+                max_context: request.max_context,
+                max_len: request.max_len,
+                split_on_word: request.split_on_word,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Run the request and return the raw whisper output.
+    pub(crate) async fn run(self) -> Result<String, String> {
+        match self {
+            AudioRequest::Transcription(request) => {
+                match llama_core::audio::audio_transcriptions(request).await {
+                    Ok(obj) => Ok(obj.text),
+                    Err(e) => Err(format!("Failed to transcribe the audio. {}", e)),
+                }
+            }
+            AudioRequest::Translation(request) => {
+                match llama_core::audio::audio_translations(request).await {
+                    Ok(obj) => Ok(obj.text),
+                    Err(e) => Err(format!("Failed to translate the audio. {}", e)),
+                }
+            }
+        }
+    }
+}
+
+/// Run the request and parse the whisper output into segments.
+///
+/// With `chunking`, a wav file whose requested range is longer than the chunk length is
+/// transcribed in overlapping windows, and the segments of the windows are stitched into a single
+/// timeline.
+pub(crate) async fn run_segments(
+    request: AudioRequest,
+    chunking: Option<Chunking>,
+) -> Result<Vec<Segment>, String> {
+    let dir = store::working_dir(&request.file().id);
+    let filename = request.file().filename.clone();
+    let audio_file = dir.join(&filename);
+
+    // the windows cover the requested range of the audio, and whisper the whole of each window
+    let (mut start, end) = request.time_range();
+    let (chunking, end) = match (chunking, audio::wav_duration(&audio_file)) {
+        (Some(chunking), Some(duration)) => {
+            (chunking, end.map_or(duration, |end| end.min(duration)))
+        }
+        _ => return request.run().await.map(transcript::parse_segments),
+    };
+    if end - start <= chunking.length {
+        return request.run().await.map(transcript::parse_segments);
+    }
+
+    let stem = Path::new(&filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let step = chunking.length - chunking.overlap;
+
+    let mut windows = Vec::new();
+    loop {
+        let window_end = (start + chunking.length).min(end);
+
+        info!(target: "stdout", "Transcribe the window {:.3}s - {:.3}s of {}", start, window_end, &filename);
+
+        let window_filename = format!("{}.chunk{}.wav", stem, windows.len());
+        let window_file = dir.join(&window_filename);
+        WavAudio::open_range(&audio_file, start, window_end)
+            .and_then(|window| window.save(&window_file))
+            .map_err(|e| e.to_string())?;
+
+        let output = request.with_filename(&window_filename).run().await;
+        let _ = fs::remove_file(&window_file);
+        windows.push((start, transcript::parse_segments(output?)));
+
+        if window_end >= end {
+            break;
+        }
+        start += step;
+    }
+
+    Ok(transcript::stitch(windows, chunking.overlap))
+}
+
+/// Transcribe the first 30 seconds of the audio file of the request, in the language whisper
+/// detects, and return the plain text.
+///
+/// Whisper detects the language from the first 30 seconds of the audio, so this short pass is
+/// transcribed in the spoken language. The rest of the file is not processed.
+pub(crate) async fn transcribe_opening(request: TranscriptionRequest) -> Result<String, String> {
+    let request = TranscriptionRequest {
+        file: copy_file_object(&request.file),
+        model: request.model,
+        language: Some("auto".to_string()),
+        offset_time: Some(0),
+        duration: Some(30_000),
+        ..Default::default()
+    };
+
+    match llama_core::audio::audio_transcriptions(request).await {
+        Ok(obj) => Ok(transcript::plain_text(&transcript::parse_segments(
+            obj.text,
+        ))),
+        Err(e) => Err(format!(
+            "Failed to transcribe the opening of the audio. {}",
+            e
+        )),
+    }
+}
+
+// copy the given file object field by field
+fn copy_file_object(file: &FileObject) -> FileObject {
+    FileObject {
+        id: file.id.clone(),
+        bytes: file.bytes,
+        created_at: file.created_at,
+        filename: file.filename.clone(),
+        object: file.object.clone(),
+        purpose: file.purpose.clone(),
+    }
+}
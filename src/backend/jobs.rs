@@ -0,0 +1,365 @@
+use super::{
+    scheduler::{self, Ticket},
+    whisper::{self, TranscriptionTask},
+};
+use crate::{
+    error,
+    utils::{json_response, now},
+};
+use hyper::{body::to_bytes, Body, Method, Request, Response, StatusCode};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::{collections::HashMap, sync::Mutex};
+use tokio::{sync::mpsc, task::AbortHandle};
+
+// transcription jobs by id
+static JOBS: OnceCell<Mutex<HashMap<String, Job>>> = OnceCell::new();
+// jobs waiting for the whisper context, run one at a time, with their places in the inference
+// queue
+static QUEUE: OnceCell<mpsc::UnboundedSender<(String, TranscriptionTask, Ticket)>> =
+    OnceCell::new();
+
+/// Status of a transcription job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+impl JobStatus {
+    fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// A transcription job, as returned by the jobs endpoints.
+#[derive(Debug, Serialize)]
+pub(crate) struct Job {
+    pub(crate) id: String,
+    pub(crate) object: String,
+    pub(crate) status: JobStatus,
+    pub(crate) created_at: u64,
+    pub(crate) started_at: Option<u64>,
+    pub(crate) completed_at: Option<u64>,
+    /// Id of the archived audio file.
+    pub(crate) file_id: String,
+    pub(crate) filename: String,
+    /// Transcript in the requested `response_format`: a JSON object for `json` and
+    /// `verbose_json`, a string otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    // aborts the running transcription
+    #[serde(skip)]
+    abort: Option<AbortHandle>,
+}
+
+/// Handle `POST /v1/audio/transcriptions/jobs`, and `GET` or `DELETE` on
+/// `/v1/audio/transcriptions/jobs/{id}`.
+///
+/// A job takes the same multipart fields as `/v1/audio/transcriptions`, except `stream`. It
+/// takes a place in the inference queue until it runs, so that a job is refused with a 429 when
+/// the queue is full, and is transcribed once the requests and jobs queued before it are done.
+pub(crate) async fn jobs_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming transcription job request");
+
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    let job_id = path
+        .strip_prefix("/v1/audio/transcriptions/jobs")
+        .unwrap_or_default()
+        .trim_start_matches('/')
+        .to_string();
+
+    let res = match (req.method(), job_id.is_empty()) {
+        (&Method::POST, true) => submit_job(req).await,
+        (&Method::GET, false) => get_job(&job_id),
+        (&Method::DELETE, false) => cancel_job(&job_id),
+        _ => {
+            let err_msg = "Invalid HTTP Method.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    };
+
+    info!(target: "stdout", "Send the transcription job response");
+
+    res
+}
+
+async fn submit_job(req: Request<Body>) -> Response<Body> {
+    // take a place in the inference queue before the upload
+    let ticket = match scheduler::enqueue() {
+        Ok(ticket) => ticket,
+        Err(response) => return response,
+    };
+
+    let task = match whisper::parse_transcription_request(req).await {
+        Ok(task) => task,
+        Err(response) => return response,
+    };
+
+    if task.stream {
+        let err_msg = "Transcription jobs do not support `stream`.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::bad_request(err_msg);
+    }
+
+    let id = format!("job_{}", uuid::Uuid::new_v4());
+    let job = Job {
+        id: id.clone(),
+        object: "transcription.job".to_string(),
+        status: JobStatus::Queued,
+        created_at: now(),
+        started_at: None,
+        completed_at: None,
+        file_id: task.request.file.id.clone(),
+        filename: task.request.file.filename.clone(),
+        result: None,
+        error: None,
+        abort: None,
+    };
+    let body = match serde_json::to_string(&job) {
+        Ok(body) => body,
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the job. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    jobs().lock().unwrap().insert(id.clone(), job);
+
+    let queue = QUEUE.get_or_init(|| {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_jobs(receiver));
+        sender
+    });
+    if queue.send((id.clone(), task, ticket)).is_err() {
+        jobs().lock().unwrap().remove(&id);
+
+        let err_msg = "Failed to queue the job. The job queue is closed.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    info!(target: "stdout", "Queued the transcription job {}", &id);
+
+    json_response(StatusCode::ACCEPTED, body)
+}
+
+fn get_job(id: &str) -> Response<Body> {
+    let body = match jobs().lock().unwrap().get(id) {
+        Some(job) => serde_json::to_string(job),
+        None => return error::not_found(format!("The job {} is not found.", id)),
+    };
+
+    match body {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the job. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+// cancel a queued or running job; a finished job is returned unchanged
+//
+// A queued job is cancelled right away. A running transcription cannot be interrupted while
+// whisper runs, as the inference is a blocking call: it is aborted at its next await point, and
+// the job stays `running` until then, so that a `cancelled` job never holds the whisper context.
+fn cancel_job(id: &str) -> Response<Body> {
+    let mut jobs = jobs().lock().unwrap();
+    let job = match jobs.get_mut(id) {
+        Some(job) => job,
+        None => return error::not_found(format!("The job {} is not found.", id)),
+    };
+
+    let status = match job.status {
+        JobStatus::Queued => {
+            job.status = JobStatus::Cancelled;
+            job.completed_at = Some(now());
+
+            info!(target: "stdout", "Cancelled the transcription job {}", id);
+
+            StatusCode::OK
+        }
+        JobStatus::Running => {
+            if let Some(abort) = job.abort.as_ref() {
+                abort.abort();
+            }
+
+            info!(target: "stdout", "Cancel the running transcription job {}", id);
+
+            StatusCode::ACCEPTED
+        }
+        _ => StatusCode::OK,
+    };
+
+    match serde_json::to_string(job) {
+        Ok(body) => json_response(status, body),
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the job. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// Forget the jobs which finished more than `ttl` seconds ago.
+pub(crate) fn evict_finished(ttl: u64) {
+    let now = now();
+
+    jobs().lock().unwrap().retain(|id, job| {
+        let expired = job.status.is_finished()
+            && job
+                .completed_at
+                .is_some_and(|completed_at| completed_at.saturating_add(ttl) <= now);
+        if expired {
+            info!(target: "stdout", "Forget the transcription job {}", id);
+        }
+
+        !expired
+    });
+}
+
+/// Number of the jobs which are queued or running.
+pub(crate) fn pending() -> usize {
+    jobs()
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|job| !job.status.is_finished())
+        .count()
+}
+
+// run the queued jobs one after another on the whisper context
+async fn run_jobs(mut receiver: mpsc::UnboundedReceiver<(String, TranscriptionTask, Ticket)>) {
+    while let Some((id, task, mut ticket)) = receiver.recv().await {
+        // a job cancelled while queued gives its place back without waiting for the context
+        if !is_queued(&id) {
+            continue;
+        }
+
+        // an accepted job waits for the whisper context as long as it takes
+        ticket.wait().await;
+
+        let handle = {
+            let mut jobs = jobs().lock().unwrap();
+            match jobs.get_mut(&id) {
+                Some(job) if job.status == JobStatus::Queued => {
+                    let handle = tokio::spawn(async move {
+                        let _ticket = ticket;
+
+                        whisper::transcribe(task).await
+                    });
+
+                    job.status = JobStatus::Running;
+                    job.started_at = Some(now());
+                    job.abort = Some(handle.abort_handle());
+
+                    handle
+                }
+                // skip the cancelled jobs
+                _ => continue,
+            }
+        };
+
+        info!(target: "stdout", "Run the transcription job {}", &id);
+
+        let result = match handle.await {
+            Ok(response) => Some(read_result(response).await),
+            Err(e) if e.is_cancelled() => None,
+            Err(e) => Some(Err(format!("The transcription task failed. {}", e))),
+        };
+
+        let mut jobs = jobs().lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            job.abort = None;
+
+            if job.status == JobStatus::Running {
+                job.completed_at = Some(now());
+                match result {
+                    Some(Ok(result)) => {
+                        job.status = JobStatus::Completed;
+                        job.result = Some(result);
+                    }
+                    Some(Err(err_msg)) => {
+                        // log
+                        error!(target: "stdout", "The transcription job {} failed. {}", &id, &err_msg);
+
+                        job.status = JobStatus::Failed;
+                        job.error = Some(err_msg);
+                    }
+                    None => job.status = JobStatus::Cancelled,
+                }
+            }
+
+            info!(target: "stdout", "The transcription job {} is {:?}", &id, job.status);
+        }
+    }
+}
+
+fn is_queued(id: &str) -> bool {
+    jobs()
+        .lock()
+        .unwrap()
+        .get(id)
+        .is_some_and(|job| job.status == JobStatus::Queued)
+}
+
+// read the transcript from the response of the transcription handler
+async fn read_result(response: Response<Body>) -> Result<serde_json::Value, String> {
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get("content-type")
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"));
+
+    let body = to_bytes(response.into_body())
+        .await
+        .map_err(|e| format!("Failed to read the transcript. {}", e))?;
+
+    if !status.is_success() {
+        return Err(String::from_utf8_lossy(&body).to_string());
+    }
+
+    match is_json {
+        true => serde_json::from_slice(&body)
+            .map_err(|e| format!("Failed to parse the transcript. {}", e)),
+        false => Ok(serde_json::Value::String(
+            String::from_utf8_lossy(&body).to_string(),
+        )),
+    }
+}
+
+fn jobs() -> &'static Mutex<HashMap<String, Job>> {
+    JOBS.get_or_init(|| Mutex::new(HashMap::new()))
+}
@@ -1,4 +1,13 @@
+pub(crate) mod health;
+pub(crate) mod inference;
+pub(crate) mod jobs;
+pub(crate) mod realtime;
+pub(crate) mod scheduler;
+pub(crate) mod stream;
+pub(crate) mod transcripts;
+pub(crate) mod uploads;
 pub(crate) mod whisper;
+pub(crate) mod ws;
 
 use crate::{error, TaskType, TASK};
 use hyper::{Body, Request, Response};
@@ -30,6 +39,39 @@ pub(crate) async fn handle_llama_request(req: Request<Body>) -> Response<Body> {
                 error::internal_server_error(err_msg)
             }
         },
+        "/v1/audio/transcriptions/jobs" => match task {
+            TaskType::Full | TaskType::Transcriptions => jobs::jobs_handler(req).await,
+            _ => {
+                let err_msg = "The current API server only support translation tasks. To support transcription and/or translation tasks, please restart the API server with `--task full` or `--task transcribe`.";
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        },
+        "/v1/audio/stream" => match task {
+            TaskType::Full | TaskType::Transcriptions => ws::audio_stream_handler(req).await,
+            _ => {
+                let err_msg = "The current API server only support translation tasks. To support transcription and/or translation tasks, please restart the API server with `--task full` or `--task transcribe`.";
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        },
+        "/v1/realtime" => match task {
+            TaskType::Full | TaskType::Transcriptions => realtime::realtime_handler(req).await,
+            _ => {
+                let err_msg = "The current API server only support translation tasks. To support transcription and/or translation tasks, please restart the API server with `--task full` or `--task transcribe`.";
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        },
         "/v1/audio/translations" => match task {
             TaskType::Full | TaskType::Translations => whisper_translations_handler(req).await,
             _ => {
@@ -41,12 +83,32 @@ pub(crate) async fn handle_llama_request(req: Request<Body>) -> Response<Body> {
                 error::internal_server_error(err_msg)
             }
         },
+        "/v1/audio/language" => whisper::whisper_language_handler(req).await,
+        "/v1/audio/transcripts" => transcripts::transcripts_handler(req).await,
         "/v1/models" => whisper::models_handler().await,
         "/v1/info" => whisper::server_info_handler().await,
+        "/v1/selftest" => health::selftest_handler(req).await,
         "/v1/files" => whisper::files_handler(req).await,
+        "/v1/uploads" => uploads::uploads_handler(req).await,
         path => {
             if path.starts_with("/v1/files/") {
                 whisper::files_handler(req).await
+            } else if path.starts_with("/v1/uploads/") {
+                uploads::uploads_handler(req).await
+            } else if path.starts_with("/v1/audio/transcripts/") {
+                transcripts::transcripts_handler(req).await
+            } else if path.starts_with("/v1/audio/transcriptions/jobs/") {
+                match task {
+                    TaskType::Full | TaskType::Transcriptions => jobs::jobs_handler(req).await,
+                    _ => {
+                        let err_msg = "The current API server only support translation tasks. To support transcription and/or translation tasks, please restart the API server with `--task full` or `--task transcribe`.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        error::internal_server_error(err_msg)
+                    }
+                }
             } else {
                 error::invalid_endpoint(path)
             }
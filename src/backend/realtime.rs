@@ -0,0 +1,500 @@
+use super::{
+    scheduler::{self, Session},
+    ws::{transcribe_samples, websocket_accept_key},
+};
+use crate::{
+    archive::ArchiveLease, audio::WavAudio, error, store, transcript, utils, LIMITS, LLAMA_API_KEY,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{SinkExt, StreamExt};
+use hyper::{upgrade::Upgraded, Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use std::fs;
+use tokio_tungstenite::{
+    tungstenite::{protocol::Role, Message},
+    WebSocketStream,
+};
+
+// prefix of the subprotocol browsers use to pass the API key
+const API_KEY_PROTOCOL_PREFIX: &str = "openai-insecure-api-key.";
+
+// longest input audio buffer, in seconds, also when `--max-audio-duration` is longer or not set
+const MAX_BUFFER_SECONDS: f64 = 600.0;
+
+/// Upgrade `GET /v1/realtime?intent=transcription` to a WebSocket speaking the transcription
+/// session events of the OpenAI Realtime API.
+///
+/// Supported client events are `transcription_session.update`, `input_audio_buffer.append`,
+/// `input_audio_buffer.commit` and `input_audio_buffer.clear`. Each committed buffer is
+/// transcribed and answered with `conversation.item.input_audio_transcription.delta` events, one
+/// per segment, and a final `conversation.item.input_audio_transcription.completed` event.
+///
+/// Turns are only committed by the client: `turn_detection` is always `null`.
+pub(crate) async fn realtime_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming realtime request");
+
+    if req.method() != Method::GET {
+        let err_msg = "Invalid HTTP Method.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    let params = utils::query_params(req.uri().query());
+    if params.get("intent").map(|intent| intent.as_str()) != Some("transcription") {
+        let err_msg =
+            "Only transcription sessions are supported. Connect with `?intent=transcription`.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::bad_request(err_msg);
+    }
+
+    let accept_key = match websocket_accept_key(&req) {
+        Some(accept_key) => accept_key,
+        None => {
+            let err_msg =
+                "Failed to upgrade the connection. The request is not a WebSocket handshake.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // browsers cannot set the authorization header, and pass the API key as a subprotocol
+    let protocols: Vec<String> = req
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|protocols| protocols.to_str().ok())
+        .map(|protocols| {
+            protocols
+                .split(',')
+                .map(|protocol| protocol.trim().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(stored_api_key) = LLAMA_API_KEY.get() {
+        if let Some(api_key) = protocols
+            .iter()
+            .find_map(|protocol| protocol.strip_prefix(API_KEY_PROTOCOL_PREFIX))
+        {
+            if api_key != stored_api_key {
+                let err_msg = "Invalid API key.";
+                return error::unauthorized(err_msg);
+            }
+        }
+    }
+
+    // the number of live sessions is bounded, as each may wait for the whisper context
+    let live = match scheduler::open_session() {
+        Ok(live) => live,
+        Err(response) => return response,
+    };
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                run_session(ws, RealtimeSession::new(live)).await;
+            }
+            Err(e) => {
+                let err_msg = format!("Failed to upgrade the connection. {}", e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+            }
+        }
+    });
+
+    let mut builder = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Accept", accept_key);
+    if protocols.iter().any(|protocol| protocol == "realtime") {
+        builder = builder.header("Sec-WebSocket-Protocol", "realtime");
+    }
+
+    match builder.body(Body::empty()) {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+// `input_audio_format` of a transcription session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputAudioFormat {
+    // 24 kHz mono little-endian 16-bit PCM
+    Pcm16,
+    // 8 kHz G.711 μ-law
+    G711Ulaw,
+    // 8 kHz G.711 A-law
+    G711Alaw,
+}
+impl InputAudioFormat {
+    fn decode(&self, bytes: &[u8]) -> WavAudio {
+        match self {
+            InputAudioFormat::Pcm16 => WavAudio::from_pcm16(bytes, self.sample_rate()),
+            InputAudioFormat::G711Ulaw => WavAudio::from_g711_ulaw(bytes, self.sample_rate()),
+            InputAudioFormat::G711Alaw => WavAudio::from_g711_alaw(bytes, self.sample_rate()),
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match self {
+            InputAudioFormat::Pcm16 => 24000,
+            InputAudioFormat::G711Ulaw | InputAudioFormat::G711Alaw => 8000,
+        }
+    }
+}
+impl std::fmt::Display for InputAudioFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InputAudioFormat::Pcm16 => write!(f, "pcm16"),
+            InputAudioFormat::G711Ulaw => write!(f, "g711_ulaw"),
+            InputAudioFormat::G711Alaw => write!(f, "g711_alaw"),
+        }
+    }
+}
+impl std::str::FromStr for InputAudioFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pcm16" => Ok(InputAudioFormat::Pcm16),
+            "g711_ulaw" => Ok(InputAudioFormat::G711Ulaw),
+            "g711_alaw" => Ok(InputAudioFormat::G711Alaw),
+            _ => Err(format!("Invalid input audio format: {}", s)),
+        }
+    }
+}
+
+// state of a transcription session
+struct RealtimeSession {
+    id: String,
+    // archive id of the audio of the session
+    file_id: String,
+    // keeps the audio of the session from being collected
+    _lease: ArchiveLease,
+    live: Session,
+    input_audio_format: InputAudioFormat,
+    model: Option<String>,
+    language: Option<String>,
+    prompt: Option<String>,
+    // audio appended since the last commit
+    buffer: Vec<f32>,
+    previous_item_id: Option<String>,
+}
+impl RealtimeSession {
+    fn new(live: Session) -> Self {
+        let file_id = format!("file_{}", uuid::Uuid::new_v4());
+
+        Self {
+            id: format!("sess_{}", uuid::Uuid::new_v4().simple()),
+            _lease: ArchiveLease::new(&file_id, false),
+            live,
+            file_id,
+            input_audio_format: InputAudioFormat::Pcm16,
+            model: None,
+            language: None,
+            prompt: None,
+            buffer: Vec::new(),
+            previous_item_id: None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "object": "realtime.transcription_session",
+            "input_audio_format": self.input_audio_format.to_string(),
+            "input_audio_transcription": {
+                "model": self.model,
+                "language": self.language,
+                "prompt": self.prompt,
+            },
+            "turn_detection": null,
+            "input_audio_noise_reduction": null,
+            "include": null,
+        })
+    }
+
+    // apply the `session` of a `transcription_session.update` event
+    fn update(&mut self, session: &Value) -> Result<(), String> {
+        if let Some(format) = session.get("input_audio_format").and_then(|v| v.as_str()) {
+            let format = format.parse::<InputAudioFormat>()?;
+            if format != self.input_audio_format && !self.buffer.is_empty() {
+                return Err(
+                    "The input audio format cannot be changed while the input audio buffer is not empty."
+                        .to_string(),
+                );
+            }
+            self.input_audio_format = format;
+        }
+
+        if let Some(transcription) = session.get("input_audio_transcription") {
+            let field = |name: &str| {
+                transcription
+                    .get(name)
+                    .and_then(|v| v.as_str())
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.to_string())
+            };
+            self.model = field("model");
+            self.language = field("language");
+            self.prompt = field("prompt");
+        }
+
+        if let Some(turn_detection) = session.get("turn_detection") {
+            if !turn_detection.is_null() {
+                warn!(target: "stdout", "`turn_detection` is not supported, the input audio buffer must be committed by the client.");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn run_session(mut ws: WebSocketStream<Upgraded>, mut session: RealtimeSession) {
+    info!(target: "stdout", "Start the realtime session {}", &session.id);
+
+    let created = json!({
+        "type": "transcription_session.created",
+        "event_id": event_id(),
+        "session": session.to_json(),
+    });
+    if ws.send(Message::Text(created.to_string())).await.is_err() {
+        return;
+    }
+
+    while let Some(message) = ws.next().await {
+        let text = match message {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                // log
+                error!(target: "stdout", "Failed to read from the realtime session {}. {}", &session.id, e);
+
+                break;
+            }
+        };
+
+        let events = handle_event(&mut session, &text).await;
+
+        let mut closed = false;
+        for event in events {
+            if let Err(e) = ws.send(Message::Text(event.to_string())).await {
+                // log
+                error!(target: "stdout", "Failed to write to the realtime session {}. {}", &session.id, e);
+
+                closed = true;
+                break;
+            }
+        }
+        if closed {
+            break;
+        }
+    }
+
+    // the audio of a session is not kept
+    let dir = store::working_dir(&session.file_id);
+    if dir.exists() {
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    info!(target: "stdout", "Close the realtime session {}", &session.id);
+}
+
+// handle a client event, and return the server events to send back
+async fn handle_event(session: &mut RealtimeSession, text: &str) -> Vec<Value> {
+    let event: Value = match serde_json::from_str(text) {
+        Ok(event) => event,
+        Err(e) => {
+            return vec![error_event(
+                None,
+                "invalid_json",
+                format!("Failed to parse the event. {}", e),
+            )]
+        }
+    };
+    let client_event_id = event.get("event_id").and_then(|v| v.as_str());
+
+    match event.get("type").and_then(|v| v.as_str()) {
+        Some("transcription_session.update") => {
+            let update = match event.get("session") {
+                Some(update) => session.update(update),
+                None => Err("Missing `session`.".to_string()),
+            };
+
+            match update {
+                Ok(()) => vec![json!({
+                    "type": "transcription_session.updated",
+                    "event_id": event_id(),
+                    "session": session.to_json(),
+                })],
+                Err(err_msg) => vec![error_event(client_event_id, "invalid_value", err_msg)],
+            }
+        }
+        Some("input_audio_buffer.append") => {
+            let audio = match event.get("audio").and_then(|v| v.as_str()) {
+                Some(audio) => audio,
+                None => {
+                    return vec![error_event(
+                        client_event_id,
+                        "missing_required_parameter",
+                        "Missing `audio`.",
+                    )]
+                }
+            };
+
+            match STANDARD.decode(audio) {
+                Ok(bytes) => {
+                    let audio = session.input_audio_format.decode(&bytes);
+
+                    // the buffer is held in memory until it is committed, so it is bounded
+                    let max_seconds = LIMITS
+                        .get()
+                        .and_then(|limits| limits.max_audio_duration)
+                        .map_or(MAX_BUFFER_SECONDS, |max| max.min(MAX_BUFFER_SECONDS));
+                    let sample_rate = session.input_audio_format.sample_rate() as f64;
+                    let seconds = (session.buffer.len() + audio.samples.len()) as f64 / sample_rate;
+                    if seconds > max_seconds {
+                        return vec![error_event(
+                            client_event_id,
+                            "input_audio_buffer_too_large",
+                            format!(
+                                "The input audio buffer would hold {:.1}s of audio, which is longer than the limit of {}s. The audio is dropped: commit the buffer more often.",
+                                seconds, max_seconds
+                            ),
+                        )];
+                    }
+
+                    session.buffer.extend(audio.samples);
+
+                    vec![]
+                }
+                Err(e) => vec![error_event(
+                    client_event_id,
+                    "invalid_value",
+                    format!("Failed to decode the base64 audio. {}", e),
+                )],
+            }
+        }
+        Some("input_audio_buffer.clear") => {
+            session.buffer.clear();
+
+            vec![json!({"type": "input_audio_buffer.cleared", "event_id": event_id()})]
+        }
+        Some("input_audio_buffer.commit") => {
+            if session.buffer.is_empty() {
+                return vec![error_event(
+                    client_event_id,
+                    "input_audio_buffer_commit_empty",
+                    "Error committing input audio buffer: the buffer is empty.",
+                )];
+            }
+
+            let item_id = format!("item_{}", uuid::Uuid::new_v4().simple());
+            let mut events = vec![json!({
+                "type": "input_audio_buffer.committed",
+                "event_id": event_id(),
+                "previous_item_id": session.previous_item_id,
+                "item_id": item_id,
+            })];
+            session.previous_item_id = Some(item_id.clone());
+
+            let audio = WavAudio::from_samples(
+                std::mem::take(&mut session.buffer),
+                session.input_audio_format.sample_rate(),
+            );
+            match transcribe_samples(
+                &session.live,
+                &session.file_id,
+                audio,
+                session.language.clone(),
+                session.prompt.clone(),
+            )
+            .await
+            {
+                Ok(segments) => {
+                    for segment in segments.iter() {
+                        events.push(json!({
+                            "type": "conversation.item.input_audio_transcription.delta",
+                            "event_id": event_id(),
+                            "item_id": item_id,
+                            "content_index": 0,
+                            "delta": segment.text,
+                        }));
+                    }
+                    events.push(json!({
+                        "type": "conversation.item.input_audio_transcription.completed",
+                        "event_id": event_id(),
+                        "item_id": item_id,
+                        "content_index": 0,
+                        "transcript": transcript::plain_text(&segments),
+                    }));
+                }
+                Err(err_msg) => {
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    events.push(json!({
+                        "type": "conversation.item.input_audio_transcription.failed",
+                        "event_id": event_id(),
+                        "item_id": item_id,
+                        "content_index": 0,
+                        "error": {
+                            "type": "transcription_error",
+                            "code": "transcription_failed",
+                            "message": err_msg,
+                            "param": null,
+                        },
+                    }));
+                }
+            }
+
+            events
+        }
+        Some(ty) => vec![error_event(
+            client_event_id,
+            "invalid_event",
+            format!("Unsupported event type: {}", ty),
+        )],
+        None => vec![error_event(
+            client_event_id,
+            "missing_required_parameter",
+            "Missing event `type`.",
+        )],
+    }
+}
+
+fn event_id() -> String {
+    format!("event_{}", uuid::Uuid::new_v4().simple())
+}
+
+fn error_event(client_event_id: Option<&str>, code: &str, message: impl Into<String>) -> Value {
+    json!({
+        "type": "error",
+        "event_id": event_id(),
+        "error": {
+            "type": "invalid_request_error",
+            "code": code,
+            "message": message.into(),
+            "param": null,
+            "event_id": client_event_id,
+        },
+    })
+}
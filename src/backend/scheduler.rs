@@ -0,0 +1,299 @@
+use crate::{
+    error::{self, ServerError},
+    shutdown,
+};
+use hyper::{Body, Response};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// admission of the inference requests to the whisper context
+static SCHEDULER: OnceCell<Scheduler> = OnceCell::new();
+
+/// Settings of the queue of the requests waiting for the whisper context.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct QueueConfig {
+    /// Maximum number of requests in the queue. More requests are refused with a 429.
+    pub(crate) depth: usize,
+    /// Seconds a request waits for the whisper context before it is refused with a 503. `None`
+    /// means no limit.
+    pub(crate) timeout: Option<u64>,
+    /// Maximum number of live audio sessions. More sessions are refused with a 429.
+    pub(crate) sessions: usize,
+}
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            depth: 32,
+            timeout: None,
+            sessions: 8,
+        }
+    }
+}
+
+/// State of the queue, reported in the `queue` field of `/v1/info`.
+#[derive(Debug, Serialize)]
+pub(crate) struct QueueStatus {
+    pub(crate) depth: usize,
+    pub(crate) timeout: Option<u64>,
+    /// Requests waiting for the whisper context.
+    pub(crate) queued: usize,
+    /// Requests running on the whisper context.
+    pub(crate) running: usize,
+    pub(crate) max_sessions: usize,
+    /// Live audio sessions open.
+    pub(crate) sessions: usize,
+}
+
+struct Scheduler {
+    config: QueueConfig,
+    // one permit per whisper context, handed out in the order they are asked for
+    contexts: Semaphore,
+    capacity: usize,
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    sessions: AtomicUsize,
+    // moving average of the time a request holds a whisper context, in milliseconds
+    average_ms: AtomicU64,
+}
+impl Scheduler {
+    fn new(config: QueueConfig) -> Self {
+        Self {
+            config,
+            contexts: Semaphore::new(1),
+            capacity: 1,
+            queued: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            sessions: AtomicUsize::new(0),
+            average_ms: AtomicU64::new(0),
+        }
+    }
+
+    // seconds until the queue is likely to have room again
+    fn retry_after(&self) -> u64 {
+        let queued = self.queued.load(Ordering::SeqCst) as u64;
+        let average_ms = self.average_ms.load(Ordering::SeqCst);
+
+        let ms = average_ms * (queued + 1) / self.capacity as u64;
+
+        ms.div_ceil(1000).max(1)
+    }
+}
+
+/// A place of a request in the inference queue, and then its hold on the whisper context.
+///
+/// The place, or the whisper context, is released when the ticket is dropped.
+#[derive(Debug)]
+pub(crate) struct Ticket {
+    permit: Option<SemaphorePermit<'static>>,
+    started_at: Option<Instant>,
+}
+impl Ticket {
+    /// Wait for the whisper context, up to the timeout of the queue. A request which waits
+    /// longer is refused with a 503.
+    #[allow(clippy::result_large_err)]
+    pub(crate) async fn acquire(&mut self) -> Result<(), Response<Body>> {
+        self.acquire_within(scheduler().config.timeout).await
+    }
+
+    /// Wait for the whisper context as long as it takes, for work which is accepted already and
+    /// has no client waiting on it, such as a transcription job.
+    pub(crate) async fn wait(&mut self) {
+        // the semaphore is never closed, and there is no timeout
+        let _ = self.acquire_within(None).await;
+    }
+
+    #[allow(clippy::result_large_err)]
+    async fn acquire_within(&mut self, timeout: Option<u64>) -> Result<(), Response<Body>> {
+        if self.permit.is_some() {
+            return Ok(());
+        }
+
+        let scheduler = scheduler();
+        let permit = match timeout {
+            Some(timeout) => match tokio::time::timeout(
+                Duration::from_secs(timeout),
+                scheduler.contexts.acquire(),
+            )
+            .await
+            {
+                Ok(permit) => permit,
+                Err(_) => {
+                    return Err(error::service_unavailable(
+                        format!(
+                            "The request waited more than {}s for the whisper context.",
+                            timeout
+                        ),
+                        scheduler.retry_after(),
+                    ))
+                }
+            },
+            None => scheduler.contexts.acquire().await,
+        };
+
+        match permit {
+            Ok(permit) => {
+                scheduler.queued.fetch_sub(1, Ordering::SeqCst);
+                scheduler.running.fetch_add(1, Ordering::SeqCst);
+
+                self.permit = Some(permit);
+                self.started_at = Some(Instant::now());
+
+                Ok(())
+            }
+            Err(e) => {
+                let err_msg = format!("Failed to wait for the whisper context. {}", e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                Err(error::internal_server_error(err_msg))
+            }
+        }
+    }
+}
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let scheduler = scheduler();
+
+        match self.started_at {
+            Some(started_at) => {
+                scheduler.running.fetch_sub(1, Ordering::SeqCst);
+
+                let elapsed_ms = started_at.elapsed().as_millis() as u64;
+                let _ = scheduler.average_ms.fetch_update(
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                    |average_ms| match average_ms {
+                        0 => Some(elapsed_ms),
+                        average_ms => Some((average_ms * 7 + elapsed_ms) / 8),
+                    },
+                );
+            }
+            None => {
+                scheduler.queued.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Set up the inference queue. Called once at startup.
+pub(crate) fn init(config: QueueConfig) -> Result<(), ServerError> {
+    SCHEDULER
+        .set(Scheduler::new(config))
+        .map_err(|_| ServerError::Operation("Failed to set `SCHEDULER`.".to_string()))
+}
+
+/// Take a place in the inference queue, or refuse the request with a 429 and a `Retry-After`
+/// header if the queue is full.
+///
+/// The place is taken before the request body is read, so that a request which cannot be served
+/// is refused before it is uploaded.
+#[allow(clippy::result_large_err)]
+pub(crate) fn enqueue() -> Result<Ticket, Response<Body>> {
+    let scheduler = scheduler();
+    let depth = scheduler.config.depth;
+
+    let taken = scheduler
+        .queued
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+            (queued < depth).then_some(queued + 1)
+        });
+
+    match taken {
+        Ok(_) => Ok(Ticket {
+            permit: None,
+            started_at: None,
+        }),
+        Err(_) => Err(error::too_many_requests(
+            format!(
+                "The inference queue is full ({} requests). Retry later.",
+                depth
+            ),
+            scheduler.retry_after(),
+        )),
+    }
+}
+
+/// A live audio session, which sends its audio to the whisper context piece by piece.
+///
+/// A session has at most one piece waiting for the whisper context at a time, so the number of
+/// sessions bounds the inference it adds to the queue. The session is closed when it is dropped.
+#[derive(Debug)]
+pub(crate) struct Session {
+    _private: (),
+}
+impl Session {
+    /// Wait for the whisper context for a piece of the audio of the session. The wait counts in
+    /// the queue, but is neither refused when the queue is full nor timed out.
+    pub(crate) async fn ticket(&self) -> Ticket {
+        scheduler().queued.fetch_add(1, Ordering::SeqCst);
+
+        let mut ticket = Ticket {
+            permit: None,
+            started_at: None,
+        };
+        ticket.wait().await;
+
+        ticket
+    }
+}
+impl Drop for Session {
+    fn drop(&mut self) {
+        scheduler().sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Open a live audio session, or refuse it with a 429 and a `Retry-After` header if the maximum
+/// number of sessions is open, or with a 503 if the server is shutting down.
+#[allow(clippy::result_large_err)]
+pub(crate) fn open_session() -> Result<Session, Response<Body>> {
+    if shutdown::is_draining() {
+        return Err(error::service_unavailable(
+            "The server is shutting down. Retry on another instance.",
+            1,
+        ));
+    }
+
+    let scheduler = scheduler();
+    let max_sessions = scheduler.config.sessions;
+
+    let taken = scheduler
+        .sessions
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |sessions| {
+            (sessions < max_sessions).then_some(sessions + 1)
+        });
+
+    match taken {
+        Ok(_) => Ok(Session { _private: () }),
+        Err(_) => Err(error::too_many_requests(
+            format!(
+                "The maximum number of live audio sessions ({}) is open. Retry later.",
+                max_sessions
+            ),
+            scheduler.retry_after(),
+        )),
+    }
+}
+
+/// Current state of the inference queue.
+pub(crate) fn status() -> QueueStatus {
+    let scheduler = scheduler();
+
+    QueueStatus {
+        depth: scheduler.config.depth,
+        timeout: scheduler.config.timeout,
+        queued: scheduler.queued.load(Ordering::SeqCst),
+        running: scheduler.running.load(Ordering::SeqCst),
+        max_sessions: scheduler.config.sessions,
+        sessions: scheduler.sessions.load(Ordering::SeqCst),
+    }
+}
+
+fn scheduler() -> &'static Scheduler {
+    SCHEDULER.get_or_init(|| Scheduler::new(QueueConfig::default()))
+}
@@ -0,0 +1,207 @@
+use super::{inference::AudioRequest, scheduler::Ticket};
+use crate::{
+    archive::ArchiveLease,
+    audio::{self, WavAudio},
+    error, store, transcript, vad,
+};
+use hyper::{body::Bytes, Body, Response};
+use serde_json::json;
+use std::{fs, path::Path};
+
+// length of the audio windows transcribed one after another, in seconds
+const STREAM_WINDOW_SECONDS: f64 = 30.0;
+
+/// Transcribe or translate the audio window by window, and push each decoded segment to the
+/// client as a Server-Sent Event.
+///
+/// The windows are at most 30 seconds long, and each ends in the longest silence of its second
+/// half, if any, so that words are not split across windows.
+///
+/// The events are `transcript.text.delta` for each segment, with its start and end times, then
+/// a final `transcript.text.done` with the full text, or `error` if the inference fails. The
+/// lease on the audio and the whisper context held by `ticket` are released once the stream ends.
+pub(crate) fn sse_response(
+    request: AudioRequest,
+    lease: ArchiveLease,
+    ticket: Ticket,
+) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let _lease = lease;
+        let _ticket = ticket;
+
+        let file_id = request.file().id.clone();
+        let filename = request.file().filename.clone();
+        let dir = store::working_dir(&file_id);
+
+        // split the requested range of the audio into windows, or fall back to a single pass if
+        // it is short or not a wav file
+        let audio_file = dir.join(&filename);
+        let (mut start, end) = request.time_range();
+        let end = audio::wav_duration(&audio_file)
+            .map(|duration| end.map_or(duration, |end| end.min(duration)));
+
+        let mut windows = Vec::new();
+        match end {
+            Some(end) if end - start > STREAM_WINDOW_SECONDS => {
+                let stem = Path::new(&filename)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default();
+
+                while start < end {
+                    let window_filename = format!("{}.window{}.wav", stem, windows.len());
+                    let saved = cut_window(&audio_file, start, STREAM_WINDOW_SECONDS, end)
+                        .and_then(|(window, window_end)| {
+                            window
+                                .save(dir.join(&window_filename))
+                                .map(|_| window_end)
+                                .map_err(|e| e.to_string())
+                        });
+                    let window_end = match saved {
+                        Ok(window_end) => window_end,
+                        Err(err_msg) => {
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
+
+                            send_event(
+                                &mut sender,
+                                json!({"type": "error", "error": {"message": err_msg}}),
+                            )
+                            .await;
+
+                            for (_, _, window_filename) in windows {
+                                if let Some(window_filename) = window_filename {
+                                    let _ = fs::remove_file(dir.join(window_filename));
+                                }
+                            }
+                            return;
+                        }
+                    };
+
+                    windows.push((
+                        start,
+                        request.with_filename(&window_filename),
+                        Some(window_filename),
+                    ));
+                    start = window_end;
+                }
+            }
+            _ => windows.push((0.0, request, None)),
+        }
+
+        let mut texts = Vec::new();
+        let mut segment_id = 0;
+        let mut finished = true;
+        let mut windows = windows.into_iter();
+        'windows: for (offset, window_request, window_filename) in windows.by_ref() {
+            let output = window_request.run().await;
+
+            if let Some(window_filename) = window_filename {
+                let _ = fs::remove_file(dir.join(window_filename));
+            }
+
+            let output = match output {
+                Ok(output) => output,
+                Err(err_msg) => {
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    send_event(
+                        &mut sender,
+                        json!({"type": "error", "error": {"message": err_msg}}),
+                    )
+                    .await;
+                    finished = false;
+                    break;
+                }
+            };
+
+            for segment in transcript::parse_segments(output) {
+                let event = json!({
+                    "type": "transcript.text.delta",
+                    "delta": segment.text,
+                    "segment": {
+                        "id": segment_id,
+                        "start": segment.start + offset,
+                        "end": segment.end + offset,
+                        "text": segment.text,
+                    },
+                });
+                segment_id += 1;
+                texts.push(segment.text);
+
+                if !send_event(&mut sender, event).await {
+                    info!(target: "stdout", "The client closed the event stream of {}.", &file_id);
+
+                    finished = false;
+                    break 'windows;
+                }
+            }
+        }
+
+        // remove the windows left after an error or a closed stream
+        for (_, _, window_filename) in windows {
+            if let Some(window_filename) = window_filename {
+                let _ = fs::remove_file(dir.join(window_filename));
+            }
+        }
+
+        if finished {
+            let text = texts
+                .iter()
+                .filter(|text| !text.is_empty())
+                .map(|text| text.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ");
+            send_event(
+                &mut sender,
+                json!({"type": "transcript.text.done", "text": text}),
+            )
+            .await;
+        }
+    });
+
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body);
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+// send a `data:` event, returning `false` if the client is gone
+async fn send_event(sender: &mut hyper::body::Sender, event: serde_json::Value) -> bool {
+    let chunk = format!("data: {}\n\n", event);
+
+    sender.send_data(Bytes::from(chunk)).await.is_ok()
+}
+
+// read the window of at most `length` seconds starting at `start`, and return it with its end
+// time; a window which stops short of `end` ends in the longest silence of its second half, if any
+fn cut_window(path: &Path, start: f64, length: f64, end: f64) -> Result<(WavAudio, f64), String> {
+    let window_end = (start + length).min(end);
+    let window = WavAudio::open_range(path, start, window_end).map_err(|e| e.to_string())?;
+    if window_end >= end {
+        return Ok((window, end));
+    }
+
+    match vad::longest_silence(&window, length / 2.0) {
+        Some(cut) => Ok((window.slice(0.0, cut), start + cut)),
+        None => Ok((window, window_end)),
+    }
+}
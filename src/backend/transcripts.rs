@@ -0,0 +1,359 @@
+use crate::{
+    error::{self, ServerError},
+    store,
+    transcript::{Segment, Word},
+    utils::{self, json_response, now},
+    vad::SpeechSegment,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// maximum number of transcripts in a page of `GET /v1/audio/transcripts`
+const MAX_LIST_LIMIT: usize = 100;
+// default number of transcripts in a page of `GET /v1/audio/transcripts`
+const DEFAULT_LIST_LIMIT: usize = 20;
+
+// archived file id and creation time of the stored transcripts, by transcript id
+static TRANSCRIPTS: OnceCell<Mutex<HashMap<String, (String, u64)>>> = OnceCell::new();
+
+/// Parameters of the request which produced a transcript.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct TranscriptParameters {
+    pub(crate) model: Option<String>,
+    pub(crate) language: Option<String>,
+    pub(crate) prompt: Option<String>,
+    pub(crate) temperature: Option<f64>,
+    pub(crate) response_format: String,
+    pub(crate) timestamp_granularities: Vec<String>,
+    /// Length of the chunks in seconds, if the audio was chunked.
+    pub(crate) chunk_length: Option<f64>,
+    pub(crate) vad: String,
+}
+
+/// A transcript stored next to its audio in `archives/{file_id}/{id}.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Transcript {
+    pub(crate) id: String,
+    pub(crate) object: String,
+    pub(crate) created_at: u64,
+    /// Id of the archived audio file.
+    pub(crate) file_id: String,
+    pub(crate) filename: String,
+    /// `transcribe` or `translate`.
+    pub(crate) task: String,
+    pub(crate) language: Option<String>,
+    /// Duration of the input audio in seconds.
+    pub(crate) duration: f64,
+    pub(crate) text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) segments: Option<Vec<Segment>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) words: Option<Vec<Word>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) speech_segments: Option<Vec<SpeechSegment>>,
+    pub(crate) parameters: TranscriptParameters,
+}
+
+/// A page of `GET /v1/audio/transcripts`.
+#[derive(Debug, Serialize)]
+struct TranscriptList {
+    object: String,
+    data: Vec<Transcript>,
+    first_id: Option<String>,
+    last_id: Option<String>,
+    has_more: bool,
+}
+
+/// Handle `GET /v1/audio/transcripts`, and `GET` or `DELETE` on
+/// `/v1/audio/transcripts/{id}`.
+///
+/// The list omits the segments and words of the transcripts. It takes the `limit`, `after`,
+/// `order` and `file_id` query parameters.
+pub(crate) async fn transcripts_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming transcript request");
+
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    let id = path
+        .strip_prefix("/v1/audio/transcripts")
+        .unwrap_or_default()
+        .trim_start_matches('/')
+        .to_string();
+
+    let res = match (req.method(), id.is_empty()) {
+        (&Method::GET, true) => list_transcripts(req.uri().query()),
+        (&Method::GET, false) => get_transcript(&id).await,
+        (&Method::DELETE, false) => delete_transcript(&id).await,
+        _ => {
+            let err_msg = "Invalid HTTP Method.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    };
+
+    info!(target: "stdout", "Send the transcript response");
+
+    res
+}
+
+/// Record the transcripts stored in the working copy. Called once at startup.
+pub(crate) fn init() -> Result<(), ServerError> {
+    let mut map = HashMap::new();
+
+    if let Ok(dirs) = fs::read_dir(store::working_root()) {
+        for dir in dirs.flatten().filter(|dir| dir.path().is_dir()) {
+            let file_id = dir.file_name().to_string_lossy().to_string();
+
+            for file in fs::read_dir(dir.path()).into_iter().flatten().flatten() {
+                let filename = file.file_name().to_string_lossy().to_string();
+                if !(filename.starts_with("transcript_") && filename.ends_with(".json")) {
+                    continue;
+                }
+
+                match load(&file.path()) {
+                    Some(transcript) => {
+                        map.insert(transcript.id, (file_id.clone(), transcript.created_at));
+                    }
+                    None => {
+                        warn!(target: "stdout", "Skip the unreadable transcript {}", file.path().display())
+                    }
+                }
+            }
+        }
+    }
+
+    info!(target: "stdout", "stored transcripts: {}", map.len());
+
+    TRANSCRIPTS
+        .set(Mutex::new(map))
+        .map_err(|_| ServerError::Operation("Failed to set `TRANSCRIPTS`.".to_string()))
+}
+
+/// Store a transcript next to its audio, and in the file store. Returns its id.
+pub(crate) async fn save(mut transcript: Transcript) -> Result<String, ServerError> {
+    transcript.id = format!("transcript_{}", uuid::Uuid::new_v4());
+    transcript.object = "audio.transcript".to_string();
+    transcript.created_at = now();
+
+    let data = serde_json::to_vec(&transcript).map_err(|e| {
+        ServerError::Operation(format!("Failed to serialize the transcript. {}", e))
+    })?;
+    let filename = format!("{}.json", &transcript.id);
+    fs::write(path(&transcript.file_id, &transcript.id), data)
+        .map_err(|e| ServerError::Operation(format!("Failed to save the transcript. {}", e)))?;
+
+    transcripts().lock().unwrap().insert(
+        transcript.id.clone(),
+        (transcript.file_id.clone(), transcript.created_at),
+    );
+
+    store::save(&transcript.file_id, &filename).await?;
+
+    info!(target: "stdout", "Stored the transcript {} of the file {}", &transcript.id, &transcript.file_id);
+
+    Ok(transcript.id)
+}
+
+fn list_transcripts(query: Option<&str>) -> Response<Body> {
+    let params = utils::query_params(query);
+
+    let limit = match params.get("limit") {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if (1..=MAX_LIST_LIMIT).contains(&limit) => limit,
+            _ => {
+                let err_msg = format!(
+                    "Invalid limit: {}. The limit must be between 1 and {}.",
+                    limit, MAX_LIST_LIMIT
+                );
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::bad_request(err_msg);
+            }
+        },
+        None => DEFAULT_LIST_LIMIT,
+    };
+
+    let descending = match params.get("order").map(|order| order.as_str()) {
+        Some("asc") => false,
+        Some("desc") | None => true,
+        Some(order) => {
+            let err_msg = format!(
+                "Invalid order: {}. Supported values are `asc` and `desc`.",
+                order
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    let file_id = params
+        .get("file_id")
+        .map(|file_id| file_id.as_str())
+        .filter(|file_id| !file_id.is_empty());
+    let after = params
+        .get("after")
+        .map(|after| after.as_str())
+        .filter(|after| !after.is_empty());
+
+    let mut entries: Vec<(String, String, u64)> = transcripts()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, (file_id, created_at))| (id.clone(), file_id.clone(), *created_at))
+        .collect();
+    entries.sort_by(|a, b| (a.2, &a.0).cmp(&(b.2, &b.0)));
+    if descending {
+        entries.reverse();
+    }
+
+    let start = match after {
+        Some(after) => match entries.iter().position(|(id, _, _)| id == after) {
+            Some(idx) => idx + 1,
+            None => {
+                let err_msg = format!("Invalid after: {}. The transcript is not found.", after);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::bad_request(err_msg);
+            }
+        },
+        None => 0,
+    };
+
+    // read one more transcript than the limit to tell whether there are more
+    let mut data: Vec<Transcript> = entries
+        .into_iter()
+        .skip(start)
+        .filter(|(_, entry_file_id, _)| file_id.is_none_or(|file_id| entry_file_id == file_id))
+        .filter_map(|(id, entry_file_id, _)| load(&path(&entry_file_id, &id)))
+        .take(limit + 1)
+        .map(|mut transcript| {
+            transcript.segments = None;
+            transcript.words = None;
+            transcript
+        })
+        .collect();
+    let has_more = data.len() > limit;
+    data.truncate(limit);
+
+    let list = TranscriptList {
+        object: "list".to_string(),
+        first_id: data.first().map(|transcript| transcript.id.clone()),
+        last_id: data.last().map(|transcript| transcript.id.clone()),
+        data,
+        has_more,
+    };
+
+    match serde_json::to_string(&list) {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the transcript list. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+async fn get_transcript(id: &str) -> Response<Body> {
+    let transcript = match find(id).await {
+        Some(transcript) => transcript,
+        None => return error::not_found(format!("The transcript {} is not found.", id)),
+    };
+
+    match serde_json::to_string(&transcript) {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the transcript. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+async fn delete_transcript(id: &str) -> Response<Body> {
+    let transcript = match find(id).await {
+        Some(transcript) => transcript,
+        None => return error::not_found(format!("The transcript {} is not found.", id)),
+    };
+
+    if let Err(e) = fs::remove_file(path(&transcript.file_id, id)) {
+        let err_msg = format!("Failed to delete the transcript {}. {}", id, e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+    transcripts().lock().unwrap().remove(id);
+
+    if let Err(e) = store::delete(&transcript.file_id, &format!("{}.json", id)).await {
+        // log
+        error!(target: "stdout", "Failed to delete the transcript {} from the file store. {}", id, e);
+    }
+
+    info!(target: "stdout", "Deleted the transcript {}", id);
+
+    let body = serde_json::json!({
+        "id": id,
+        "object": "audio.transcript",
+        "deleted": true,
+    });
+    json_response(StatusCode::OK, body.to_string())
+}
+
+// the stored transcript with the given id, fetched from the file store if it was evicted from
+// the working copy; a transcript deleted with its audio is forgotten
+async fn find(id: &str) -> Option<Transcript> {
+    let file_id = transcripts().lock().unwrap().get(id)?.0.clone();
+
+    let path = path(&file_id, id);
+    if !path.is_file() && store::is_remote() {
+        if let Err(e) = store::fetch(&file_id).await {
+            error!(target: "stdout", "Failed to fetch the transcript {}. {}", id, e);
+        }
+    }
+
+    match load(&path) {
+        Some(transcript) => Some(transcript),
+        None => {
+            transcripts().lock().unwrap().remove(id);
+
+            None
+        }
+    }
+}
+
+fn load(path: &Path) -> Option<Transcript> {
+    let data = fs::read(path).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+fn path(file_id: &str, id: &str) -> PathBuf {
+    store::working_path(file_id, &format!("{}.json", id))
+}
+
+fn transcripts() -> &'static Mutex<HashMap<String, (String, u64)>> {
+    TRANSCRIPTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
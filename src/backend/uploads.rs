@@ -0,0 +1,582 @@
+use crate::{
+    error, form,
+    index::{self, FileRecord},
+    sniff, store,
+    utils::{json_response, now},
+};
+use endpoints::files::FileObject;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// seconds after which a pending upload expires
+const UPLOAD_TTL: u64 = 3600;
+// name of the state file in the directory of an upload
+const STATE_FILENAME: &str = "upload.json";
+
+// serializes the updates of the state files
+static UPLOADS_LOCK: Mutex<()> = Mutex::new(());
+// uploads whose parts are being assembled, outside of the lock; they are not pending meanwhile
+static COMPLETING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Status of an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UploadStatus {
+    Pending,
+    Completed,
+    Cancelled,
+    Expired,
+}
+
+/// An upload, as returned by the uploads endpoints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Upload {
+    pub(crate) id: String,
+    pub(crate) object: String,
+    /// Expected size of the file, in bytes.
+    pub(crate) bytes: u64,
+    pub(crate) created_at: u64,
+    pub(crate) filename: String,
+    pub(crate) purpose: String,
+    pub(crate) status: UploadStatus,
+    pub(crate) expires_at: u64,
+    /// The file assembled from the parts, once the upload is completed.
+    pub(crate) file: Option<FileObject>,
+    /// Owner of the file, from the `user` field of the creation request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+}
+
+/// A part of an upload.
+#[derive(Debug, Serialize)]
+pub(crate) struct UploadPart {
+    pub(crate) id: String,
+    pub(crate) object: String,
+    pub(crate) created_at: u64,
+    pub(crate) upload_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateUploadRequest {
+    filename: String,
+    purpose: String,
+    bytes: u64,
+    #[serde(default)]
+    user: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompleteUploadRequest {
+    part_ids: Vec<String>,
+}
+
+/// Handle the uploads endpoints:
+///
+/// - `POST /v1/uploads`: Create an upload from a JSON body with `filename`, `purpose`,
+///   `bytes` and, optionally, the `user` who owns the file.
+/// - `POST /v1/uploads/{upload_id}/parts`: Add a part, sent in the `data` field of a multipart
+///   body.
+/// - `POST /v1/uploads/{upload_id}/complete`: Assemble the parts listed in `part_ids`, in order,
+///   into a file of the archive.
+/// - `POST /v1/uploads/{upload_id}/cancel`: Cancel an upload.
+///
+/// Parts are saved on disk as they arrive, so an interrupted upload resumes by sending the
+/// missing parts only. Pending uploads expire after an hour, and the expired uploads are
+/// deleted by the garbage collector of the archives.
+pub(crate) async fn uploads_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming upload request");
+
+    if req.method() != Method::POST {
+        let err_msg = "Invalid HTTP Method.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    let uri_path = req.uri().path().trim_end_matches('/').to_string();
+    let segments: Vec<&str> = uri_path.split('/').collect();
+
+    let res = match segments.as_slice() {
+        ["", "v1", "uploads"] => create_upload(req).await,
+        ["", "v1", "uploads", id, "parts"] => add_part(id, req).await,
+        ["", "v1", "uploads", id, "complete"] => complete_upload(id, req).await,
+        ["", "v1", "uploads", id, "cancel"] => cancel_upload(id),
+        _ => {
+            let err_msg = format!("unsupported uri path: {}", uri_path);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::invalid_endpoint(err_msg)
+        }
+    };
+
+    info!(target: "stdout", "Send the upload response");
+
+    res
+}
+
+async fn create_upload(req: Request<Body>) -> Response<Body> {
+    let body_bytes = match form::read_body(req).await {
+        Ok(body_bytes) => body_bytes,
+        Err(response) => return response,
+    };
+    let request: CreateUploadRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            let err_msg = format!("Invalid upload request. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // keep the base name only, as the file is saved under this name
+    let filename = match Path::new(&request.filename).file_name() {
+        Some(filename) => filename.to_string_lossy().to_string(),
+        None => {
+            let err_msg = format!("Invalid filename: {}", &request.filename);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    let created_at = now();
+    let upload = Upload {
+        id: format!("upload_{}", uuid::Uuid::new_v4()),
+        object: "upload".to_string(),
+        bytes: request.bytes,
+        created_at,
+        filename,
+        purpose: request.purpose,
+        status: UploadStatus::Pending,
+        expires_at: created_at + UPLOAD_TTL,
+        file: None,
+        user: request
+            .user
+            .map(|user| user.trim().to_string())
+            .filter(|user| !user.is_empty()),
+    };
+
+    let _lock = UPLOADS_LOCK.lock().unwrap();
+    if let Err(e) = fs::create_dir_all(upload_dir(&upload.id)) {
+        let err_msg = format!("Failed to create the upload directory. {}", e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+    if let Err(err_msg) = save_upload(&upload) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    info!(target: "stdout", "Created the upload {} of {} ({} bytes)", &upload.id, &upload.filename, upload.bytes);
+
+    upload_response(&upload)
+}
+
+async fn add_part(upload_id: &str, req: Request<Body>) -> Response<Body> {
+    if let Err(response) = pending_upload(upload_id) {
+        return response;
+    }
+
+    let mut multipart = match form::multipart(req) {
+        Ok(multipart) => multipart,
+        Err(response) => return response,
+    };
+
+    let part = UploadPart {
+        id: format!("part_{}", uuid::Uuid::new_v4()),
+        object: "upload.part".to_string(),
+        created_at: now(),
+        upload_id: upload_id.to_string(),
+    };
+
+    // write the part under a temporary name as it arrives, so that a dropped write leaves no
+    // partial part
+    let path = upload_dir(upload_id).join(&part.id);
+    let tmp_path = path.with_extension("tmp");
+    let mut bytes: Option<u64> = None;
+    loop {
+        let field = match form::next_field(&mut multipart).await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(response) => return response,
+        };
+
+        if field.name() == Some("data") {
+            match form::save_field(field, &tmp_path).await {
+                Ok(size) => bytes = Some(size),
+                Err(response) => return response,
+            }
+            break;
+        }
+    }
+    let bytes = match bytes {
+        Some(bytes) => bytes,
+        None => {
+            let err_msg = "The `data` field is required.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    if let Err(e) = fs::rename(&tmp_path, &path) {
+        let _ = fs::remove_file(&tmp_path);
+        let err_msg = format!("Failed to save the part. {}", e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    info!(target: "stdout", "Added the part {} of {} bytes to the upload {}", &part.id, bytes, upload_id);
+
+    match serde_json::to_string(&part) {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the part. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+async fn complete_upload(upload_id: &str, req: Request<Body>) -> Response<Body> {
+    let body_bytes = match form::read_body(req).await {
+        Ok(body_bytes) => body_bytes,
+        Err(response) => return response,
+    };
+    let request: CompleteUploadRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            let err_msg = format!("Invalid complete request. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // claim the upload, so that it is neither completed twice nor cancelled while its parts are
+    // assembled outside of the lock
+    let (mut upload, bytes) = {
+        let _lock = UPLOADS_LOCK.lock().unwrap();
+
+        let upload = match pending_upload(upload_id) {
+            Ok(upload) => upload,
+            Err(response) => return response,
+        };
+
+        // check the parts before assembling them
+        let dir = upload_dir(upload_id);
+        let mut bytes = 0;
+        for part_id in request.part_ids.iter() {
+            let metadata = match is_valid_id(part_id, "part_")
+                .then(|| fs::metadata(dir.join(part_id)).ok())
+                .flatten()
+            {
+                Some(metadata) => metadata,
+                None => {
+                    let err_msg = format!(
+                        "The part {} is not found in the upload {}.",
+                        part_id, upload_id
+                    );
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::bad_request(err_msg);
+                }
+            };
+            bytes += metadata.len();
+        }
+        if bytes != upload.bytes {
+            let err_msg = format!(
+                "The parts add up to {} bytes, but the upload expects {} bytes.",
+                bytes, upload.bytes
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+
+        COMPLETING.lock().unwrap().push(upload_id.to_string());
+
+        (upload, bytes)
+    };
+
+    let file = FileObject {
+        id: format!("file_{}", uuid::Uuid::new_v4()),
+        bytes,
+        created_at: now(),
+        filename: upload.filename.clone(),
+        object: "file".to_string(),
+        purpose: upload.purpose.clone(),
+    };
+    if let Err(response) = assemble(&upload_dir(upload_id), &request.part_ids, &file) {
+        let _ = fs::remove_dir_all(store::working_dir(&file.id));
+        COMPLETING.lock().unwrap().retain(|id| id != upload_id);
+
+        return response;
+    }
+
+    {
+        let _lock = UPLOADS_LOCK.lock().unwrap();
+
+        upload.status = UploadStatus::Completed;
+        upload.file = Some(file.clone());
+        if let Err(err_msg) = save_upload(&upload) {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+        }
+        remove_parts(upload_id);
+        COMPLETING.lock().unwrap().retain(|id| id != upload_id);
+    }
+
+    if let Err(e) = store::save(&file.id, &file.filename).await {
+        let err_msg = format!(
+            "Failed to save the file {} in the file store. {}",
+            &file.id, e
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+    index::put(FileRecord::new(
+        &file,
+        file.filename.clone(),
+        upload.user.clone(),
+    ))
+    .await;
+
+    info!(target: "stdout", "Completed the upload {} as the file {}", upload_id, &file.id);
+
+    upload_response(&upload)
+}
+
+fn cancel_upload(upload_id: &str) -> Response<Body> {
+    let _lock = UPLOADS_LOCK.lock().unwrap();
+
+    let mut upload = match pending_upload(upload_id) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    upload.status = UploadStatus::Cancelled;
+    if let Err(err_msg) = save_upload(&upload) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+    remove_parts(upload_id);
+
+    info!(target: "stdout", "Cancelled the upload {}", upload_id);
+
+    upload_response(&upload)
+}
+
+// the upload with the given id, if it is still pending
+#[allow(clippy::result_large_err)]
+fn pending_upload(upload_id: &str) -> Result<Upload, Response<Body>> {
+    let upload = match load_upload(upload_id) {
+        Some(upload) => upload,
+        None => {
+            return Err(error::not_found(format!(
+                "The upload {} is not found.",
+                upload_id
+            )))
+        }
+    };
+
+    if COMPLETING.lock().unwrap().iter().any(|id| id == upload_id) {
+        let err_msg = format!("The upload {} is being completed.", upload_id);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(error::bad_request(err_msg));
+    }
+
+    let status = match upload.status {
+        UploadStatus::Pending if upload.expires_at <= now() => UploadStatus::Expired,
+        status => status,
+    };
+    if status != UploadStatus::Pending {
+        let err_msg = format!(
+            "The upload {} is {}.",
+            upload_id,
+            serde_json::to_string(&status)
+                .unwrap_or_default()
+                .trim_matches('"')
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(error::bad_request(err_msg));
+    }
+
+    Ok(upload)
+}
+
+// concatenate the parts into the file of the archive, and check its content
+#[allow(clippy::result_large_err)]
+fn assemble(dir: &Path, part_ids: &[String], file: &FileObject) -> Result<(), Response<Body>> {
+    if let Err(err_msg) = concatenate(dir, part_ids, file) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(error::internal_server_error(err_msg));
+    }
+
+    // classify the file by its content, whatever its extension
+    let path = store::working_path(&file.id, &file.filename);
+    if sniff::sniff_file(&path).is_none() {
+        let err_msg = format!(
+            "The content of {} is not supported. Supported files are audio (wav, mp3, aac, m4a, ogg, flac, webm, aiff), png images and UTF-8 text.",
+            &file.filename
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(error::unsupported_media_type(err_msg));
+    }
+
+    Ok(())
+}
+
+fn concatenate(dir: &Path, part_ids: &[String], file: &FileObject) -> Result<(), String> {
+    let archive_dir = store::working_dir(&file.id);
+    fs::create_dir_all(&archive_dir)
+        .map_err(|e| format!("Failed to create the archive directory. {}", e))?;
+
+    let mut output = File::create(archive_dir.join(&file.filename)).map_err(|e| {
+        format!(
+            "Failed to create archive document {}. {}",
+            &file.filename, e
+        )
+    })?;
+    for part_id in part_ids {
+        let mut part = File::open(dir.join(part_id))
+            .map_err(|e| format!("Failed to open the part {}. {}", part_id, e))?;
+        io::copy(&mut part, &mut output)
+            .map_err(|e| format!("Failed to assemble the part {}. {}", part_id, e))?;
+    }
+
+    output
+        .sync_all()
+        .map_err(|e| format!("Failed to write archive document {}. {}", &file.filename, e))
+}
+
+fn load_upload(upload_id: &str) -> Option<Upload> {
+    if !is_valid_id(upload_id, "upload_") {
+        return None;
+    }
+
+    let data = fs::read(upload_dir(upload_id).join(STATE_FILENAME)).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+fn save_upload(upload: &Upload) -> Result<(), String> {
+    let data =
+        serde_json::to_vec(upload).map_err(|e| format!("Failed to serialize the upload. {}", e))?;
+
+    let path = upload_dir(&upload.id).join(STATE_FILENAME);
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, data)
+        .and_then(|_| fs::rename(&tmp_path, &path))
+        .map_err(|e| format!("Failed to save the upload. {}", e))
+}
+
+// delete the parts of an upload, keeping its state
+fn remove_parts(upload_id: &str) {
+    if let Ok(entries) = fs::read_dir(upload_dir(upload_id)) {
+        for entry in entries.flatten() {
+            if entry.file_name() != STATE_FILENAME {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// Delete the uploads past their expiry time, whatever their status.
+pub(crate) fn evict_expired() {
+    let _lock = UPLOADS_LOCK.lock().unwrap();
+
+    let entries = match fs::read_dir("uploads") {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let now = now();
+    for entry in entries.flatten() {
+        let upload_id = entry.file_name().to_string_lossy().to_string();
+        let expired = match load_upload(&upload_id) {
+            Some(upload) => {
+                upload.expires_at <= now && !COMPLETING.lock().unwrap().contains(&upload_id)
+            }
+            None => false,
+        };
+
+        if expired && fs::remove_dir_all(entry.path()).is_ok() {
+            info!(target: "stdout", "Deleted the expired upload {}", &upload_id);
+        }
+    }
+}
+
+fn upload_dir(upload_id: &str) -> PathBuf {
+    Path::new("uploads").join(upload_id)
+}
+
+// ids are used as path components, so only ids generated by the server are accepted
+fn is_valid_id(id: &str, prefix: &str) -> bool {
+    id.strip_prefix(prefix).is_some_and(|rest| {
+        !rest.is_empty() && rest.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+fn upload_response(upload: &Upload) -> Response<Body> {
+    match serde_json::to_string(upload) {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the upload. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
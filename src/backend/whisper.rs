@@ -1,18 +1,44 @@
-use crate::{error, SERVER_INFO, USE_AUDIO_PREPROCESSOR};
+use super::{
+    inference::{self, AudioRequest, Chunking},
+    scheduler, stream,
+    transcripts::{self, Transcript, TranscriptParameters},
+};
+use crate::{
+    archive::ArchiveLease,
+    audio, error, form,
+    index::{self, FileRecord, SortOrder},
+    sniff, store,
+    transcript::{self, ResponseFormat, Segment, TimestampGranularity, VerboseTranscript, Word},
+    utils,
+    vad::{Speech, SpeechSegment, VadKind},
+    LIMITS, SERVER_INFO, USE_AUDIO_PREPROCESSOR, VAD,
+};
 use endpoints::{
     audio::{transcription::TranscriptionRequest, translation::TranslationRequest},
     files::{DeleteFileStatus, FileObject},
     models::{ListModelsResponse, Model},
 };
-use hyper::{body::to_bytes, Body, Method, Request, Response};
-use multipart::server::{Multipart, ReadEntry, ReadEntryResult};
-use multipart_2021 as multipart;
-use std::{
-    fs::{self, File},
-    io::{Cursor, Read, Write},
-    path::Path,
-    time::SystemTime,
-};
+use hyper::{header::HeaderValue, Body, Method, Request, Response};
+use multer::Field;
+use serde::Serialize;
+use std::{fs, path::Path, time::SystemTime};
+
+// maximum and default number of files in a page of `GET /v1/files`
+const MAX_LIST_LIMIT: usize = 10000;
+// temporary name of an uploaded audio file in its archive directory, until it is converted
+const UPLOAD_FILENAME: &str = "upload.part";
+
+/// A transcription request parsed from its multipart body, with the options of this server.
+pub(crate) struct TranscriptionTask {
+    pub(crate) request: TranscriptionRequest,
+    pub(crate) response_format: ResponseFormat,
+    pub(crate) timestamp_granularities: Vec<TimestampGranularity>,
+    pub(crate) stream: bool,
+    pub(crate) chunking: Option<Chunking>,
+    pub(crate) vad: VadKind,
+    /// Keeps the audio from being collected until the request is answered.
+    pub(crate) lease: ArchiveLease,
+}
 
 pub(crate) async fn whisper_transcriptions_handler(req: Request<Body>) -> Response<Body> {
     // log
@@ -20,1124 +46,1135 @@ pub(crate) async fn whisper_transcriptions_handler(req: Request<Body>) -> Respon
 
     let res = match *req.method() {
         Method::POST => {
-            let boundary = "boundary=";
-
-            let boundary = match req.headers().get("content-type").and_then(|ct| {
-                let ct = ct.to_str().ok()?;
-                let idx = ct.find(boundary)?;
-                Some(ct[idx + boundary.len()..].to_string())
-            }) {
-                Some(boundary) => boundary,
-                None => {
-                    let err_msg = "Failed to get the boundary from the request.";
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    return error::internal_server_error(err_msg);
-                }
+            // take a place in the inference queue before the upload
+            let mut ticket = match scheduler::enqueue() {
+                Ok(ticket) => ticket,
+                Err(response) => return response,
             };
 
-            let req_body = req.into_body();
-            let body_bytes = match to_bytes(req_body).await {
-                Ok(body_bytes) => body_bytes,
-                Err(e) => {
-                    let err_msg = format!("Fail to read buffer from request body. {}", e);
+            let task = match parse_transcription_request(req).await {
+                Ok(task) => task,
+                Err(response) => return response,
+            };
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+            // wait for the whisper context
+            if let Err(response) = ticket.acquire().await {
+                return response;
+            }
 
-                    return error::internal_server_error(err_msg);
-                }
-            };
+            if task.stream {
+                debug!(target: "stdout", "Request: {}", serde_json::to_string(&task.request).unwrap());
 
-            let cursor = Cursor::new(body_bytes.to_vec());
+                info!(target: "stdout", "Stream the audio transcription response");
 
-            let mut multipart = Multipart::with_body(cursor, boundary);
+                return stream::sse_response(
+                    AudioRequest::Transcription(task.request),
+                    task.lease,
+                    ticket,
+                );
+            }
 
-            // create a transcription request
-            let mut request = TranscriptionRequest::default();
-            while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
-                match &*field.headers.name {
-                    "file" => {
-                        let mut filename = match field.headers.filename {
-                            Some(filename) => filename,
-                            None => {
-                                let err_msg =
-                                    "Failed to upload the target file. The filename is not provided.";
+            transcribe(task).await
+        }
+        _ => {
+            let err_msg = "Invalid HTTP Method.";
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
+            error::internal_server_error(err_msg)
+        }
+    };
 
-                        let mut buffer = Vec::new();
-                        let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                            Ok(size_in_bytes) => size_in_bytes,
-                            Err(e) => {
-                                let err_msg = format!("Failed to read the target file. {}", e);
+    info!(target: "stdout", "Send the audio transcription response");
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    res
+}
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
+/// Parse the multipart body of a transcription request, and validate its options.
+pub(crate) async fn parse_transcription_request(
+    req: Request<Body>,
+) -> Result<TranscriptionTask, Response<Body>> {
+    let mut multipart = match form::multipart(req) {
+        Ok(multipart) => multipart,
+        Err(response) => return Err(response),
+    };
 
-                        // create a unique file id
-                        let id = format!("file_{}", uuid::Uuid::new_v4());
+    // create a transcription request
+    let mut request = TranscriptionRequest::default();
+    let mut timestamp_granularities: Vec<TimestampGranularity> = Vec::new();
+    let mut stream = false;
+    let mut chunk_length: Option<f64> = None;
+    let mut chunk_overlap: Option<f64> = None;
+    let mut vad: Option<String> = None;
+    let mut lease: Option<ArchiveLease> = None;
+    let mut user: Option<String> = None;
+    let mut uploaded = false;
+    loop {
+        let field = match form::next_field(&mut multipart).await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(response) => return Err(response),
+        };
 
-                        // create wav audio file to store the converted audio data
-                        let path = Path::new("archives");
-                        if !path.exists() {
-                            fs::create_dir(path).unwrap();
-                        }
-                        let file_path = path.join(&id);
-                        if !file_path.exists() {
-                            fs::create_dir(&file_path).unwrap();
-                        }
-                        let output_file = file_path.join(&filename);
-                        let output_wav_file = output_file.with_extension("wav");
-                        filename = output_wav_file
-                            .file_name()
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .to_string();
+        match field.name().unwrap_or_default() {
+            "file" => {
+                let filename = match field.file_name() {
+                    Some(filename) => filename.to_string(),
+                    None => {
+                        let err_msg =
+                            "Failed to upload the target file. The filename is not provided.";
 
                         // log
-                        info!(target: "stdout", "file_id: {}, file_name: {}", &id, &filename);
+                        error!(target: "stdout", "{}", &err_msg);
 
-                        let use_audio_preprocessor = match USE_AUDIO_PREPROCESSOR.get() {
-                            Some(use_audio_preprocessor) => *use_audio_preprocessor,
-                            None => false,
-                        };
+                        return Err(error::internal_server_error(err_msg));
+                    }
+                };
 
-                        if use_audio_preprocessor {
-                            info!(target: "stdout", "Pre-processing the audio file...");
+                request.file = match archive_audio_file(&filename, field).await {
+                    Ok(file) => file,
+                    Err(response) => return Err(response),
+                };
+                lease = Some(ArchiveLease::new(&request.file.id, true));
+                uploaded = true;
+            }
+            "file_id" => match form::is_text(&field) {
+                true => {
+                    let file_id = match field.text().await {
+                        Ok(file_id) => file_id,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `file_id`. {}", e);
 
-                            // create a audio converter
-                            let converter = wavup::AudioConverterBuilder::new(
-                                output_wav_file.to_string_lossy(),
-                                llama_core::metadata::whisper::WHISPER_SAMPLE_RATE as u32,
-                            )
-                            .build();
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                            // convert to a wav audio file with the given sample rate
-                            if let Err(e) = converter.convert_audio_from_bytes(&buffer) {
-                                let err_msg = format!("Failed to convert audio. {}", e);
-                                error!(target: "stdout", "{}", &err_msg);
-                                return error::internal_server_error(err_msg);
-                            }
-                        } else {
-                            // write the audio data to the wav file
-                            let mut file = match File::create(output_wav_file) {
-                                Ok(file) => file,
-                                Err(e) => {
-                                    let err_msg = format!("Failed to create the wav file. {}", e);
-                                    error!(target: "stdout", "{}", &err_msg);
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
-                            if let Err(e) = file.write_all(&buffer) {
-                                let err_msg = format!(
-                                    "Failed to write the audio data to the wav file. {}",
-                                    e
-                                );
-                                error!(target: "stdout", "{}", &err_msg);
-                                return error::internal_server_error(err_msg);
-                            };
+                            return Err(error::internal_server_error(err_msg));
                         }
+                    };
+
+                    request.file = match archived_audio_file(file_id.trim()).await {
+                        Ok(file) => file,
+                        Err(response) => return Err(response),
+                    };
+                    // a converted copy of the stored file belongs to this request
+                    let converted = request.file.id != file_id.trim();
+                    lease = Some(ArchiveLease::new(&request.file.id, converted));
+                    uploaded = false;
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `file_id`. The `file_id` field in the request should be a text field.";
 
-                        let created_at =
-                            match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                                Ok(n) => n.as_secs(),
-                                Err(_) => {
-                                    let err_msg = "Failed to get the current time.";
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
-
-                        // create a file object
-                        request.file = FileObject {
-                            id,
-                            bytes: size_in_bytes as u64,
-                            created_at,
-                            filename,
-                            object: "file".to_string(),
-                            purpose: "assistants".to_string(),
-                        };
-                    }
-                    "model" => {
-                        match field.is_text() {
-                            true => {
-                                let mut model = String::new();
-
-                                if let Err(e) = field.data.read_to_string(&mut model) {
-                                    let err_msg = format!("Failed to read the model. {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::internal_server_error(err_msg);
-                                }
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                request.model = Some(model);
-                            }
-                            false => {
-                                let err_msg =
-                                    "Failed to get the model name. The model field in the request should be a text field.";
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "model" => {
+                match form::is_text(&field) {
+                    true => {
+                        let model = match field.text().await {
+                            Ok(model) => model,
+                            Err(e) => {
+                                let err_msg = format!("Failed to read the model. {}", e);
 
                                 // log
                                 error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
+                                return Err(error::internal_server_error(err_msg));
                             }
-                        }
-                    }
-                    "language" => match field.is_text() {
-                        true => {
-                            let mut language = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut language) {
-                                let err_msg = format!("Failed to read the prompt. {}", e);
+                        };
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                        request.model = Some(model);
+                    }
+                    false => {
+                        let err_msg =
+                                    "Failed to get the model name. The model field in the request should be a text field.";
 
-                                return error::internal_server_error(err_msg);
-                            }
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                            request.language = Some(language);
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the spoken language info. The language field in the request should be a text field.";
+                        return Err(error::internal_server_error(err_msg));
+                    }
+                }
+            }
+            "language" => match form::is_text(&field) {
+                true => {
+                    let language = match field.text().await {
+                        Ok(language) => language,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read the prompt. {}", e);
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return Err(error::internal_server_error(err_msg));
                         }
-                    },
-                    "prompt" => match field.is_text() {
-                        true => {
-                            let mut prompt = String::new();
+                    };
 
-                            if let Err(e) = field.data.read_to_string(&mut prompt) {
-                                let err_msg = format!("Failed to read the prompt. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    request.language = Some(language);
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get the spoken language info. The language field in the request should be a text field.";
 
-                                return error::internal_server_error(err_msg);
-                            }
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                            request.prompt = Some(prompt);
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the prompt. The prompt field in the request should be a text field.";
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "prompt" => match form::is_text(&field) {
+                true => {
+                    let prompt = match field.text().await {
+                        Ok(prompt) => prompt,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read the prompt. {}", e);
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return Err(error::internal_server_error(err_msg));
                         }
-                    },
-                    "response_format" => {
-                        match field.is_text() {
-                            true => {
-                                let mut response_format = String::new();
+                    };
 
-                                if let Err(e) = field.data.read_to_string(&mut response_format) {
-                                    let err_msg =
-                                        format!("Failed to read the response format. {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                    request.prompt = Some(prompt);
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get the prompt. The prompt field in the request should be a text field.";
 
-                                    return error::internal_server_error(err_msg);
-                                }
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                request.response_format = Some(response_format);
-                            }
-                            false => {
-                                let err_msg =
-                                    "Failed to get the response format. The response format field in the request should be a text field.";
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "response_format" => {
+                match form::is_text(&field) {
+                    true => {
+                        let response_format = match field.text().await {
+                            Ok(response_format) => response_format,
+                            Err(e) => {
+                                let err_msg = format!("Failed to read the response format. {}", e);
 
                                 // log
                                 error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
+                                return Err(error::internal_server_error(err_msg));
                             }
-                        }
-                    }
-                    "temperature" => {
-                        match field.is_text() {
-                            true => {
-                                let mut temperature = String::new();
+                        };
 
-                                if let Err(e) = field.data.read_to_string(&mut temperature) {
-                                    let err_msg = format!("Failed to read the temperature. {}", e);
+                        request.response_format = Some(response_format);
+                    }
+                    false => {
+                        let err_msg =
+                                    "Failed to get the response format. The response format field in the request should be a text field.";
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::internal_server_error(err_msg);
-                                }
+                        return Err(error::internal_server_error(err_msg));
+                    }
+                }
+            }
+            "temperature" => {
+                match form::is_text(&field) {
+                    true => {
+                        let temperature = match field.text().await {
+                            Ok(temperature) => temperature,
+                            Err(e) => {
+                                let err_msg = format!("Failed to read the temperature. {}", e);
 
-                                match temperature.trim().parse::<f64>() {
-                                    Ok(temp) => {
-                                        request.temperature = Some(temp);
-                                    }
-                                    Err(e) => {
-                                        let err_msg =
-                                            format!("Failed to parse the temperature. {}", e);
+                                // log
+                                error!(target: "stdout", "{}", &err_msg);
 
-                                        // log
-                                        error!(target: "stdout", "{}", &err_msg);
+                                return Err(error::internal_server_error(err_msg));
+                            }
+                        };
 
-                                        return error::internal_server_error(err_msg);
-                                    }
-                                }
+                        match temperature.trim().parse::<f64>() {
+                            Ok(temp) => {
+                                request.temperature = Some(temp);
                             }
-                            false => {
-                                let err_msg =
-                                    "Failed to get the temperature. The temperature field in the request should be a text field.";
+                            Err(e) => {
+                                let err_msg = format!("Failed to parse the temperature. {}", e);
 
                                 // log
                                 error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
+                                return Err(error::internal_server_error(err_msg));
                             }
                         }
                     }
-                    "timestamp_granularities" => unimplemented!(),
-                    "detect_language" => match field.is_text() {
-                        true => {
-                            let mut detect_language: String = String::new();
+                    false => {
+                        let err_msg =
+                                    "Failed to get the temperature. The temperature field in the request should be a text field.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                            if let Err(e) = field.data.read_to_string(&mut detect_language) {
-                                let err_msg = format!("Failed to read `detect_language`. {}", e);
+                        return Err(error::internal_server_error(err_msg));
+                    }
+                }
+            }
+            "timestamp_granularities" | "timestamp_granularities[]" => {
+                match form::is_text(&field) {
+                    true => {
+                        let granularities = match field.text().await {
+                            Ok(granularities) => granularities,
+                            Err(e) => {
+                                let err_msg =
+                                    format!("Failed to read `timestamp_granularities`. {}", e);
 
                                 // log
                                 error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
+                                return Err(error::internal_server_error(err_msg));
                             }
+                        };
 
-                            match detect_language.parse::<bool>() {
-                                Ok(detect_language) => {
-                                    request.detect_language = Some(detect_language)
+                        for granularity in granularities.split(',') {
+                            match granularity.parse::<TimestampGranularity>() {
+                                Ok(granularity) => {
+                                    if !timestamp_granularities.contains(&granularity) {
+                                        timestamp_granularities.push(granularity);
+                                    }
                                 }
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `detect_language`. Reason: {}", e);
-
+                                Err(err_msg) => {
                                     // log
                                     error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::bad_request(err_msg);
+                                    return Err(error::bad_request(err_msg));
                                 }
                             }
                         }
-                        false => {
-                            let err_msg =
-                                "Failed to get `detect_language`. The `detect_language` field in the request should be a text field.";
+                    }
+                    false => {
+                        let err_msg =
+                                "Failed to get `timestamp_granularities`. The `timestamp_granularities` field in the request should be a text field.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return Err(error::internal_server_error(err_msg));
+                    }
+                }
+            }
+            "detect_language" => match form::is_text(&field) {
+                true => {
+                    let detect_language = match field.text().await {
+                        Ok(detect_language) => detect_language,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `detect_language`. {}", e);
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return Err(error::internal_server_error(err_msg));
                         }
-                    },
-                    "offset_time" => match field.is_text() {
-                        true => {
-                            let mut offset_time: String = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut offset_time) {
-                                let err_msg = format!("Failed to read `offset_time`. {}", e);
+                    };
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    match detect_language.parse::<bool>() {
+                        Ok(detect_language) => request.detect_language = Some(detect_language),
+                        Err(e) => {
+                            let err_msg =
+                                format!("Failed to parse `detect_language`. Reason: {}", e);
 
-                                return error::internal_server_error(err_msg);
-                            }
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                            match offset_time.parse::<u64>() {
-                                Ok(offset_time) => request.offset_time = Some(offset_time),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `offset_time`. Reason: {}", e);
+                            return Err(error::bad_request(err_msg));
+                        }
+                    }
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `detect_language`. The `detect_language` field in the request should be a text field.";
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get `offset_time`. The `offset_time` field in the request should be a text field.";
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "offset_time" => match form::is_text(&field) {
+                true => {
+                    let offset_time = match field.text().await {
+                        Ok(offset_time) => offset_time,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `offset_time`. {}", e);
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return Err(error::internal_server_error(err_msg));
                         }
-                    },
-                    "duration" => match field.is_text() {
-                        true => {
-                            let mut duration: String = String::new();
+                    };
 
-                            if let Err(e) = field.data.read_to_string(&mut duration) {
-                                let err_msg = format!("Failed to read `duration`. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match duration.parse::<u64>() {
-                                Ok(duration) => request.duration = Some(duration),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `duration`. Reason: {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get `duration`. The `duration` field in the request should be a text field.";
+                    match offset_time.parse::<u64>() {
+                        Ok(offset_time) => request.offset_time = Some(offset_time),
+                        Err(e) => {
+                            let err_msg = format!("Failed to parse `offset_time`. Reason: {}", e);
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return Err(error::bad_request(err_msg));
                         }
-                    },
-                    "max_context" => match field.is_text() {
-                        true => {
-                            let mut max_context: String = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut max_context) {
-                                let err_msg = format!("Failed to read `max_context`. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    }
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `offset_time`. The `offset_time` field in the request should be a text field.";
 
-                                return error::internal_server_error(err_msg);
-                            }
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                            match max_context.parse::<i32>() {
-                                Ok(max_context) => request.max_context = Some(max_context),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `max_context`. Reason: {}", e);
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "duration" => match form::is_text(&field) {
+                true => {
+                    let duration = match field.text().await {
+                        Ok(duration) => duration,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `duration`. {}", e);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
+                            return Err(error::internal_server_error(err_msg));
                         }
-                        false => {
-                            let err_msg =
-                                "Failed to get `max_context`. The `max_context` field in the request should be a text field.";
+                    };
+
+                    match duration.parse::<u64>() {
+                        Ok(duration) => request.duration = Some(duration),
+                        Err(e) => {
+                            let err_msg = format!("Failed to parse `duration`. Reason: {}", e);
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return Err(error::bad_request(err_msg));
                         }
-                    },
-                    "max_len" => match field.is_text() {
-                        true => {
-                            let mut max_len: String = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut max_len) {
-                                let err_msg = format!("Failed to read `max_len`. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    }
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `duration`. The `duration` field in the request should be a text field.";
 
-                                return error::internal_server_error(err_msg);
-                            }
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                            match max_len.parse::<u64>() {
-                                Ok(max_len) => request.max_len = Some(max_len),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `max_len`. Reason: {}", e);
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "max_context" => match form::is_text(&field) {
+                true => {
+                    let max_context = match field.text().await {
+                        Ok(max_context) => max_context,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `max_context`. {}", e);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
+                            return Err(error::internal_server_error(err_msg));
                         }
-                        false => {
-                            let err_msg =
-                                "Failed to get `max_len`. The `max_len` field in the request should be a text field.";
+                    };
+
+                    match max_context.parse::<i32>() {
+                        Ok(max_context) => request.max_context = Some(max_context),
+                        Err(e) => {
+                            let err_msg = format!("Failed to parse `max_context`. Reason: {}", e);
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return Err(error::bad_request(err_msg));
                         }
-                    },
-                    "split_on_word" => match field.is_text() {
-                        true => {
-                            let mut split_on_word: String = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut split_on_word) {
-                                let err_msg = format!("Failed to read `split_on_word`. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    }
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `max_context`. The `max_context` field in the request should be a text field.";
 
-                                return error::internal_server_error(err_msg);
-                            }
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                            match split_on_word.parse::<bool>() {
-                                Ok(split_on_word) => request.split_on_word = Some(split_on_word),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `split_on_word`. Reason: {}", e);
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "max_len" => match form::is_text(&field) {
+                true => {
+                    let max_len = match field.text().await {
+                        Ok(max_len) => max_len,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `max_len`. {}", e);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
+                            return Err(error::internal_server_error(err_msg));
                         }
-                        false => {
-                            let err_msg =
-                                "Failed to get `split_on_word`. The `split_on_word` field in the request should be a text field.";
+                    };
+
+                    match max_len.parse::<u64>() {
+                        Ok(max_len) => request.max_len = Some(max_len),
+                        Err(e) => {
+                            let err_msg = format!("Failed to parse `max_len`. Reason: {}", e);
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return Err(error::bad_request(err_msg));
                         }
-                    },
-                    _ => {
-                        let err_msg = format!("Invalid field name: {}", &field.headers.name);
-
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
-
-                        return error::internal_server_error(err_msg);
                     }
                 }
-            }
-
-            if Some(true) == request.detect_language {
-                request.language = Some("auto".to_string());
-            }
-
-            debug!(target: "stdout", "Request: {}", serde_json::to_string(&request).unwrap());
-
-            let obj = match llama_core::audio::audio_transcriptions(request).await {
-                Ok(obj) => obj,
-                Err(e) => {
-                    let err_msg = format!("Failed to transcribe the audio. {}", e);
+                false => {
+                    let err_msg =
+                                "Failed to get `max_len`. The `max_len` field in the request should be a text field.";
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
+                    return Err(error::internal_server_error(err_msg));
                 }
-            };
+            },
+            "split_on_word" => match form::is_text(&field) {
+                true => {
+                    let split_on_word = match field.text().await {
+                        Ok(split_on_word) => split_on_word,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `split_on_word`. {}", e);
 
-            // serialize chat completion object
-            let s = match serde_json::to_string(&obj) {
-                Ok(s) => s,
-                Err(e) => {
-                    let err_msg = format!("Failed to serialize transcription object. {}", e);
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+                            return Err(error::internal_server_error(err_msg));
+                        }
+                    };
 
-                    return error::internal_server_error(err_msg);
-                }
-            };
+                    match split_on_word.parse::<bool>() {
+                        Ok(split_on_word) => request.split_on_word = Some(split_on_word),
+                        Err(e) => {
+                            let err_msg = format!("Failed to parse `split_on_word`. Reason: {}", e);
 
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .body(Body::from(s));
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+                            return Err(error::bad_request(err_msg));
+                        }
+                    }
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `split_on_word`. The `split_on_word` field in the request should be a text field.";
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
-                    error::internal_server_error(err_msg)
+                    return Err(error::internal_server_error(err_msg));
                 }
-            }
-        }
-        _ => {
-            let err_msg = "Invalid HTTP Method.";
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+            },
+            "stream" => match form::is_text(&field) {
+                true => {
+                    let value = match field.text().await {
+                        Ok(value) => value,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `stream`. {}", e);
 
-            error::internal_server_error(err_msg)
-        }
-    };
-
-    info!(target: "stdout", "Send the audio transcription response");
-
-    res
-}
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-pub(crate) async fn whisper_translations_handler(req: Request<Body>) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming audio translation request");
+                            return Err(error::internal_server_error(err_msg));
+                        }
+                    };
 
-    let res = match *req.method() {
-        Method::POST => {
-            let boundary = "boundary=";
-
-            let boundary = match req.headers().get("content-type").and_then(|ct| {
-                let ct = ct.to_str().ok()?;
-                let idx = ct.find(boundary)?;
-                Some(ct[idx + boundary.len()..].to_string())
-            }) {
-                Some(boundary) => boundary,
-                None => {
-                    let err_msg = "Failed to get the boundary from the request.";
+                    match value.trim().parse::<bool>() {
+                        Ok(value) => stream = value,
+                        Err(e) => {
+                            let err_msg = format!("Failed to parse `stream`. Reason: {}", e);
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
+                            return Err(error::bad_request(err_msg));
+                        }
+                    }
                 }
-            };
-
-            let req_body = req.into_body();
-            let body_bytes = match to_bytes(req_body).await {
-                Ok(body_bytes) => body_bytes,
-                Err(e) => {
-                    let err_msg = format!("Fail to read buffer from request body. {}", e);
+                false => {
+                    let err_msg =
+                                "Failed to get `stream`. The `stream` field in the request should be a text field.";
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
+                    return Err(error::internal_server_error(err_msg));
                 }
-            };
+            },
+            "chunk_length" => match form::is_text(&field) {
+                true => {
+                    let value = match field.text().await {
+                        Ok(value) => value,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `chunk_length`. {}", e);
 
-            let cursor = Cursor::new(body_bytes.to_vec());
-
-            let mut multipart = Multipart::with_body(cursor, boundary);
-
-            // create a transcription request
-            let mut request = TranslationRequest::default();
-            while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
-                match &*field.headers.name {
-                    "file" => {
-                        let mut filename = match field.headers.filename {
-                            Some(filename) => filename,
-                            None => {
-                                let err_msg =
-                                    "Failed to upload the target file. The filename is not provided.";
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-
-                        let mut buffer = Vec::new();
-                        let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                            Ok(size_in_bytes) => size_in_bytes,
-                            Err(e) => {
-                                let err_msg = format!("Failed to read the target file. {}", e);
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                            return Err(error::internal_server_error(err_msg));
+                        }
+                    };
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
+                    match value.trim().parse::<f64>() {
+                        Ok(value) => chunk_length = Some(value),
+                        Err(e) => {
+                            let err_msg = format!("Failed to parse `chunk_length`. Reason: {}", e);
 
-                        // create a unique file id
-                        let id = format!("file_{}", uuid::Uuid::new_v4());
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                        // create wav audio file to store the converted audio data
-                        let path = Path::new("archives");
-                        if !path.exists() {
-                            fs::create_dir(path).unwrap();
-                        }
-                        let file_path = path.join(&id);
-                        if !file_path.exists() {
-                            fs::create_dir(&file_path).unwrap();
+                            return Err(error::bad_request(err_msg));
                         }
-                        let output_file = file_path.join(&filename);
-                        let output_wav_file = output_file.with_extension("wav");
-                        filename = output_wav_file
-                            .file_name()
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .to_string();
-
-                        // log
-                        info!(target: "stdout", "file_id: {}, file_name: {}", &id, &filename);
+                    }
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `chunk_length`. The `chunk_length` field in the request should be a text field.";
 
-                        let use_audio_preprocessor = match USE_AUDIO_PREPROCESSOR.get() {
-                            Some(use_audio_preprocessor) => *use_audio_preprocessor,
-                            None => false,
-                        };
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                        if use_audio_preprocessor {
-                            info!(target: "stdout", "Pre-processing the audio file...");
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "chunk_overlap" => match form::is_text(&field) {
+                true => {
+                    let value = match field.text().await {
+                        Ok(value) => value,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `chunk_overlap`. {}", e);
 
-                            // create a audio converter
-                            let converter = wavup::AudioConverterBuilder::new(
-                                output_wav_file.to_string_lossy(),
-                                llama_core::metadata::whisper::WHISPER_SAMPLE_RATE as u32,
-                            )
-                            .build();
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                            // convert to a wav audio file with the given sample rate
-                            if let Err(e) = converter.convert_audio_from_bytes(&buffer) {
-                                let err_msg = format!("Failed to convert audio. {}", e);
-                                error!(target: "stdout", "{}", &err_msg);
-                                return error::internal_server_error(err_msg);
-                            }
-                        } else {
-                            // write the audio data to the wav file
-                            let mut file = match File::create(output_wav_file) {
-                                Ok(file) => file,
-                                Err(e) => {
-                                    let err_msg = format!("Failed to create the wav file. {}", e);
-                                    error!(target: "stdout", "{}", &err_msg);
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
-                            if let Err(e) = file.write_all(&buffer) {
-                                let err_msg = format!(
-                                    "Failed to write the audio data to the wav file. {}",
-                                    e
-                                );
-                                error!(target: "stdout", "{}", &err_msg);
-                                return error::internal_server_error(err_msg);
-                            };
+                            return Err(error::internal_server_error(err_msg));
                         }
+                    };
 
-                        // // create a audio converter
-                        // let converter = wavup::AudioConverterBuilder::new(
-                        //     output_wav_file.to_string_lossy(),
-                        //     llama_core::metadata::whisper::WHISPER_SAMPLE_RATE as u32,
-                        // )
-                        // .build();
-
-                        // // convert to a wav audio file with the given sample rate
-                        // if let Err(e) = converter.convert_audio_from_bytes(&buffer) {
-                        //     let err_msg = format!("Failed to convert audio. {}", e);
-                        //     error!(target: "stdout", "{}", &err_msg);
-                        //     return error::internal_server_error(err_msg);
-                        // }
-
-                        let created_at =
-                            match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                                Ok(n) => n.as_secs(),
-                                Err(_) => {
-                                    let err_msg = "Failed to get the current time.";
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                    match value.trim().parse::<f64>() {
+                        Ok(value) => chunk_overlap = Some(value),
+                        Err(e) => {
+                            let err_msg = format!("Failed to parse `chunk_overlap`. Reason: {}", e);
 
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                        // create a file object
-                        request.file = FileObject {
-                            id,
-                            bytes: size_in_bytes as u64,
-                            created_at,
-                            filename,
-                            object: "file".to_string(),
-                            purpose: "assistants".to_string(),
-                        };
+                            return Err(error::bad_request(err_msg));
+                        }
                     }
-                    "model" => {
-                        if field.is_text() {
-                            let mut model = String::new();
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `chunk_overlap`. The `chunk_overlap` field in the request should be a text field.";
 
-                            if let Err(e) = field.data.read_to_string(&mut model) {
-                                let err_msg = format!("Failed to read the model. {}", e);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "vad" => match form::is_text(&field) {
+                true => {
+                    let value = match field.text().await {
+                        Ok(value) => value,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `vad`. {}", e);
 
-                                return error::internal_server_error(err_msg);
-                            }
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                            request.model = Some(model);
+                            return Err(error::internal_server_error(err_msg));
                         }
-                    }
-                    "prompt" => {
-                        match field.is_text() {
-                            true => {
-                                let mut prompt = String::new();
-
-                                if let Err(e) = field.data.read_to_string(&mut prompt) {
-                                    let err_msg = format!("Failed to read the prompt. {}", e);
+                    };
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                    vad = Some(value);
+                }
+                false => {
+                    let err_msg =
+                                "Failed to get `vad`. The `vad` field in the request should be a text field.";
 
-                                    return error::internal_server_error(err_msg);
-                                }
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                request.prompt = Some(prompt);
-                            }
-                            false => {
-                                let err_msg =
-                                    "Failed to get the prompt. The prompt field in the request should be a text field.";
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            "user" => match form::is_text(&field) {
+                true => {
+                    let value = match field.text().await {
+                        Ok(value) => value,
+                        Err(e) => {
+                            let err_msg = format!("Failed to read `user`. {}", e);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
-                            }
+                            return Err(error::internal_server_error(err_msg));
                         }
-                    }
-                    "response_format" => {
-                        match field.is_text() {
-                            true => {
-                                let mut response_format = String::new();
-
-                                if let Err(e) = field.data.read_to_string(&mut response_format) {
-                                    let err_msg =
-                                        format!("Failed to read the response format. {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::internal_server_error(err_msg);
-                                }
+                    };
 
-                                request.response_format = Some(response_format);
-                            }
-                            false => {
-                                let err_msg =
-                                    "Failed to get the response format. The response format field in the request should be a text field.";
+                    user = Some(value.trim().to_string()).filter(|user| !user.is_empty());
+                }
+                false => {
+                    let err_msg =
+                        "Failed to get `user`. The `user` field in the request should be a text field.";
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        }
-                    }
-                    "temperature" => {
-                        match field.is_text() {
-                            true => {
-                                let mut temperature = String::new();
+                    return Err(error::internal_server_error(err_msg));
+                }
+            },
+            _ => {
+                let err_msg = format!("Invalid field name: {}", field.name().unwrap_or_default());
 
-                                if let Err(e) = field.data.read_to_string(&mut temperature) {
-                                    let err_msg = format!("Failed to read the temperature. {}", e);
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                return Err(error::internal_server_error(err_msg));
+            }
+        }
+    }
 
-                                    return error::internal_server_error(err_msg);
-                                }
+    if Some(true) == request.detect_language {
+        request.language = Some("auto".to_string());
+    }
 
-                                match temperature.trim().parse::<f64>() {
-                                    Ok(temp) => {
-                                        request.temperature = Some(temp);
-                                    }
-                                    Err(e) => {
-                                        let err_msg =
-                                            format!("Failed to parse the temperature. {}", e);
+    let response_format = match request.response_format.as_deref() {
+        Some(response_format) => match response_format.parse::<ResponseFormat>() {
+            Ok(response_format) => response_format,
+            Err(err_msg) => {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                                        // log
-                                        error!(target: "stdout", "{}", &err_msg);
+                return Err(error::bad_request(err_msg));
+            }
+        },
+        None => ResponseFormat::default(),
+    };
 
-                                        return error::internal_server_error(err_msg);
-                                    }
-                                }
-                            }
-                            false => {
-                                let err_msg =
-                                    "Failed to get the temperature. The temperature field in the request should be a text field.";
+    // a stream sends the segments as they are decoded, in windows of its own
+    if stream {
+        let err_msg = match response_format {
+            ResponseFormat::Json => [
+                (
+                    "timestamp_granularities",
+                    !timestamp_granularities.is_empty(),
+                ),
+                ("chunk_length", chunk_length.is_some()),
+                ("chunk_overlap", chunk_overlap.is_some()),
+                ("vad", vad.is_some()),
+            ]
+            .into_iter()
+            .find(|(_, set)| *set)
+            .map(|(name, _)| format!("`stream` does not support `{}`.", name)),
+            response_format => Some(format!(
+                "`stream` does not support the `{}` response format.",
+                response_format
+            )),
+        };
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+        if let Some(err_msg) = err_msg {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        }
-                    }
-                    "language" => {
-                        match field.is_text() {
-                            true => {
-                                let mut language = String::new();
+            return Err(error::bad_request(err_msg));
+        }
+    }
 
-                                if let Err(e) = field.data.read_to_string(&mut language) {
-                                    let err_msg = format!("Failed to read the prompt. {}", e);
+    let chunking = match Chunking::for_request(chunk_length, chunk_overlap) {
+        Ok(chunking) => chunking,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+            return Err(error::bad_request(err_msg));
+        }
+    };
+    let vad = match VadKind::for_request(vad.as_deref()) {
+        Ok(vad) => vad,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::internal_server_error(err_msg);
-                                }
+            return Err(error::bad_request(err_msg));
+        }
+    };
 
-                                request.language = Some(language);
-                            }
-                            false => {
-                                let err_msg =
-                                    "Failed to get the spoken language info. The language field in the request should be a text field.";
+    // the audio uploaded with the request is owned by its `user`, which may come after the file
+    if let Some(owner) = user.filter(|_| uploaded) {
+        if let Some(mut record) = index::get(&request.file.id) {
+            record.owner = Some(owner);
+            index::put(record).await;
+        }
+    }
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    let lease = lease.unwrap_or_else(|| ArchiveLease::new(&request.file.id, false));
+
+    Ok(TranscriptionTask {
+        request,
+        lease,
+        response_format,
+        timestamp_granularities,
+        stream,
+        chunking,
+        vad,
+    })
+}
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        }
-                    }
-                    "detect_language" => match field.is_text() {
-                        true => {
-                            let mut detect_language: String = String::new();
+/// Transcribe the audio of a parsed request, and render the transcript in the requested format.
+pub(crate) async fn transcribe(task: TranscriptionTask) -> Response<Body> {
+    run_task(task, false).await
+}
 
-                            if let Err(e) = field.data.read_to_string(&mut detect_language) {
-                                let err_msg = format!("Failed to read `detect_language`. {}", e);
+/// Translate the audio of a parsed request into English, and render it in the requested format.
+async fn translate(task: TranscriptionTask) -> Response<Body> {
+    run_task(task, true).await
+}
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+/// Run a parsed request on the whisper context. Only the transcripts are stored.
+async fn run_task(task: TranscriptionTask, translate: bool) -> Response<Body> {
+    let TranscriptionTask {
+        request,
+        response_format,
+        timestamp_granularities,
+        chunking,
+        vad,
+        lease: _lease,
+        ..
+    } = task;
+
+    // `timestamp_granularities` only applies to `verbose_json`, which defaults to segment-level timestamps
+    let (include_segments, include_words) = match response_format {
+        ResponseFormat::VerboseJson => (
+            timestamp_granularities.is_empty()
+                || timestamp_granularities.contains(&TimestampGranularity::Segment),
+            timestamp_granularities.contains(&TimestampGranularity::Word),
+        ),
+        _ => {
+            if !timestamp_granularities.is_empty() {
+                warn!(target: "stdout", "`timestamp_granularities` is ignored for the `{}` response format.", response_format);
+            }
 
-                                return error::internal_server_error(err_msg);
-                            }
+            (true, false)
+        }
+    };
 
-                            match detect_language.parse::<bool>() {
-                                Ok(detect_language) => {
-                                    request.detect_language = Some(detect_language)
-                                }
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `detect_language`. Reason: {}", e);
+    // keep the info required to render the response
+    let file_id = request.file.id.clone();
+    let filename = request.file.filename.clone();
+    let language = request.language.clone();
+    let parameters = TranscriptParameters {
+        model: request.model.clone(),
+        language: request.language.clone(),
+        prompt: request.prompt.clone(),
+        temperature: request.temperature,
+        response_format: response_format.to_string(),
+        timestamp_granularities: timestamp_granularities
+            .iter()
+            .map(|granularity| granularity.to_string())
+            .collect(),
+        chunk_length: chunking.map(|chunking| chunking.length),
+        vad: vad.to_string(),
+    };
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+    debug!(target: "stdout", "Request: {}", serde_json::to_string(&request).unwrap());
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get `detect_language`. The `detect_language` field in the request should be a text field.";
+    let task_name = match translate {
+        true => "translate",
+        false => "transcribe",
+    };
+    let audio_file = store::working_path(&file_id, &filename);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    let mut request = match translate {
+        true => AudioRequest::Translation(translation_request(request)),
+        false => AudioRequest::Transcription(request),
+    };
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "offset_time" => match field.is_text() {
-                        true => {
-                            let mut offset_time: String = String::new();
+    // skip the long silences of the requested range of the audio
+    let speech = match vad.detector() {
+        Some(detector) => {
+            let min_silence = VAD.get().map(|vad| vad.min_silence).unwrap_or(1.0);
+            let range = request.time_range();
 
-                            if let Err(e) = field.data.read_to_string(&mut offset_time) {
-                                let err_msg = format!("Failed to read `offset_time`. {}", e);
+            match Speech::detect(&audio_file, detector.as_ref(), min_silence, range) {
+                Ok(speech) => Some(speech),
+                Err(e) => {
+                    warn!(target: "stdout", "Failed to detect the speech of {}. Transcribe the whole audio. {}", &filename, e);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let silent = speech.as_ref().is_some_and(|speech| speech.is_silent());
 
-                                return error::internal_server_error(err_msg);
-                            }
+    if let Some(speech_filename) = speech.as_ref().and_then(|speech| speech.filename()) {
+        request = request.with_filename(speech_filename);
+    }
 
-                            match offset_time.parse::<u64>() {
-                                Ok(offset_time) => request.offset_time = Some(offset_time),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `offset_time`. Reason: {}", e);
+    // with word-level timestamps, the audio is transcribed once at the word level, and the
+    // segments are rebuilt from the words
+    let (words, segments) = match (include_words, silent) {
+        (_, true) => (
+            include_words.then(Vec::new),
+            include_segments.then(Vec::new),
+        ),
+        // whisper emits one word per segment with `max_len = 1` and `split_on_word = true`
+        (true, false) => {
+            match inference::run_segments(request.word_level(), chunking).await {
+                Ok(segments) => (Some(transcript::words_from_segments(segments)), None),
+                Err(e) => {
+                    let err_msg =
+                        format!("Failed to {} the audio at the word level. {}", task_name, e);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get `offset_time`. The `offset_time` field in the request should be a text field.";
+                    return error::internal_server_error(err_msg);
+                }
+            }
+        }
+        (false, false) => match inference::run_segments(request, chunking).await {
+            Ok(segments) => (None, Some(segments)),
+            Err(err_msg) => {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+                return error::internal_server_error(err_msg);
+            }
+        },
+    };
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "duration" => match field.is_text() {
-                        true => {
-                            let mut duration: String = String::new();
+    // report the times in the original audio
+    let (words, segments, speech_segments) = match speech {
+        Some(speech) => (
+            words.map(|words| speech.remap_words(words)),
+            segments.map(|segments| speech.remap_segments(segments)),
+            Some(speech.segments.clone()),
+        ),
+        None => (words, segments, None),
+    };
 
-                            if let Err(e) = field.data.read_to_string(&mut duration) {
-                                let err_msg = format!("Failed to read `duration`. {}", e);
+    // rebuild the segments once the words are in the original timeline, in which the skipped
+    // silences are pauses
+    let segments = match (segments, &words) {
+        (None, Some(words)) if include_segments => Some(transcript::segments_from_words(words)),
+        (segments, _) => segments,
+    };
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    // keep the transcript, so that it can be fetched again without running the inference
+    let transcript = (!translate).then(|| Transcript {
+        id: String::new(),
+        object: String::new(),
+        created_at: 0,
+        file_id,
+        filename,
+        task: task_name.to_string(),
+        language: language.clone().filter(|language| language != "auto"),
+        duration: transcript_duration(&segments, &words, &audio_file),
+        text: transcript_text(&segments, &words),
+        segments: segments.clone(),
+        words: words.clone(),
+        speech_segments: speech_segments.clone(),
+        parameters,
+    });
+    let transcript_id = match transcript {
+        Some(transcript) => match transcripts::save(transcript).await {
+            Ok(transcript_id) => Some(transcript_id),
+            Err(e) => {
+                warn!(target: "stdout", "Failed to store the transcript. {}", e);
 
-                                return error::internal_server_error(err_msg);
-                            }
+                None
+            }
+        },
+        None => None,
+    };
 
-                            match duration.parse::<u64>() {
-                                Ok(duration) => request.duration = Some(duration),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `duration`. Reason: {}", e);
+    let mut response = transcript_response(
+        segments,
+        words,
+        speech_segments,
+        response_format,
+        task_name,
+        language,
+        audio_file,
+    );
+    if let Some(value) = transcript_id.and_then(|id| HeaderValue::from_str(&id).ok()) {
+        response.headers_mut().insert("x-transcript-id", value);
+    }
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+    response
+}
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get `duration`. The `duration` field in the request should be a text field.";
+pub(crate) async fn whisper_translations_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio translation request");
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    let res = match *req.method() {
+        Method::POST => {
+            // take a place in the inference queue before the upload
+            let mut ticket = match scheduler::enqueue() {
+                Ok(ticket) => ticket,
+                Err(response) => return response,
+            };
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "max_context" => match field.is_text() {
-                        true => {
-                            let mut max_context: String = String::new();
+            // a translation request takes the same fields as a transcription request
+            let task = match parse_transcription_request(req).await {
+                Ok(task) => task,
+                Err(response) => return response,
+            };
 
-                            if let Err(e) = field.data.read_to_string(&mut max_context) {
-                                let err_msg = format!("Failed to read `max_context`. {}", e);
+            // wait for the whisper context
+            if let Err(response) = ticket.acquire().await {
+                return response;
+            }
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            if task.stream {
+                debug!(target: "stdout", "Request: {}", serde_json::to_string(&task.request).unwrap());
 
-                                return error::internal_server_error(err_msg);
-                            }
+                info!(target: "stdout", "Stream the audio translation response");
 
-                            match max_context.parse::<i32>() {
-                                Ok(max_context) => request.max_context = Some(max_context),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `max_context`. Reason: {}", e);
+                return stream::sse_response(
+                    AudioRequest::Translation(translation_request(task.request)),
+                    task.lease,
+                    ticket,
+                );
+            }
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+            translate(task).await
+        }
+        _ => {
+            let err_msg = "Invalid HTTP Method.";
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get `max_context`. The `max_context` field in the request should be a text field.";
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+            error::internal_server_error(err_msg)
+        }
+    };
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "max_len" => match field.is_text() {
-                        true => {
-                            let mut max_len: String = String::new();
+    info!(target: "stdout", "Send the audio translation response");
 
-                            if let Err(e) = field.data.read_to_string(&mut max_len) {
-                                let err_msg = format!("Failed to read `max_len`. {}", e);
+    res
+}
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+/// Turn a parsed transcription request into the translation request of the same audio.
+#[allow(clippy::needless_update)]
+fn translation_request(request: TranscriptionRequest) -> TranslationRequest {
+    TranslationRequest {
+        file: request.file,
+        model: request.model,
+        language: request.language,
+        prompt: request.prompt,
+        response_format: request.response_format,
+        temperature: request.temperature,
+        detect_language: request.detect_language,
+        offset_time: request.offset_time,
+        duration: request.duration,
+        max_context: request.max_context,
+        max_len: request.max_len,
+        split_on_word: request.split_on_word,
+        ..Default::default()
+    }
+}
 
-                                return error::internal_server_error(err_msg);
-                            }
+/// Transcribe the first 30 seconds of an audio file in the language whisper detects, to tell the
+/// spoken language from a short pass instead of a full transcription.
+///
+/// The multipart body takes the same `file` field as the transcription endpoint. The whisper
+/// backend returns the text but not the detected language, so the response only holds the text.
+pub(crate) async fn whisper_language_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming language detection request");
 
-                            match max_len.parse::<u64>() {
-                                Ok(max_len) => request.max_len = Some(max_len),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `max_len`. Reason: {}", e);
+    let res = match *req.method() {
+        Method::POST => {
+            // take a place in the inference queue before the upload
+            let mut ticket = match scheduler::enqueue() {
+                Ok(ticket) => ticket,
+                Err(response) => return response,
+            };
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+            let mut multipart = match form::multipart(req) {
+                Ok(multipart) => multipart,
+                Err(response) => return response,
+            };
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get `max_len`. The `max_len` field in the request should be a text field.";
+            let mut request = TranscriptionRequest::default();
+            let mut lease: Option<ArchiveLease> = None;
+            loop {
+                let field = match form::next_field(&mut multipart).await {
+                    Ok(Some(field)) => field,
+                    Ok(None) => break,
+                    Err(response) => return response,
+                };
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+                match field.name().unwrap_or_default() {
+                    "file" if lease.is_some() => {
+                        let err_msg =
+                            "Failed to upload the target file. The `file` field is repeated.";
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "split_on_word" => match field.is_text() {
-                        true => {
-                            let mut split_on_word: String = String::new();
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                            if let Err(e) = field.data.read_to_string(&mut split_on_word) {
-                                let err_msg = format!("Failed to read `split_on_word`. {}", e);
+                        return error::bad_request(err_msg);
+                    }
+                    "file" => {
+                        let filename = match field.file_name() {
+                            Some(filename) => filename.to_string(),
+                            None => {
+                                let err_msg =
+                                    "Failed to upload the target file. The filename is not provided.";
 
                                 // log
                                 error!(target: "stdout", "{}", &err_msg);
 
                                 return error::internal_server_error(err_msg);
                             }
+                        };
 
-                            match split_on_word.parse::<bool>() {
-                                Ok(split_on_word) => request.split_on_word = Some(split_on_word),
+                        request.file = match archive_audio_file(&filename, field).await {
+                            Ok(file) => file,
+                            Err(response) => return response,
+                        };
+                        lease = Some(ArchiveLease::new(&request.file.id, true));
+                    }
+                    "model" => match form::is_text(&field) {
+                        true => {
+                            let model = match field.text().await {
+                                Ok(model) => model,
                                 Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse `split_on_word`. Reason: {}", e);
+                                    let err_msg = format!("Failed to read the model. {}", e);
 
                                     // log
                                     error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::bad_request(err_msg);
+                                    return error::internal_server_error(err_msg);
                                 }
-                            }
+                            };
+
+                            request.model = Some(model);
                         }
                         false => {
                             let err_msg =
-                                "Failed to get `split_on_word`. The `split_on_word` field in the request should be a text field.";
+                                "Failed to get the model name. The model field in the request should be a text field.";
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
@@ -1146,7 +1183,8 @@ pub(crate) async fn whisper_translations_handler(req: Request<Body>) -> Response
                         }
                     },
                     _ => {
-                        let err_msg = format!("Invalid field name: {}", &field.headers.name);
+                        let err_msg =
+                            format!("Invalid field name: {}", field.name().unwrap_or_default());
 
                         // log
                         error!(target: "stdout", "{}", &err_msg);
@@ -1156,30 +1194,26 @@ pub(crate) async fn whisper_translations_handler(req: Request<Body>) -> Response
                 }
             }
 
-            if Some(true) == request.detect_language {
-                request.language = Some("auto".to_string());
-            }
-
-            debug!(target: "stdout", "Request: {}", serde_json::to_string(&request).unwrap());
-
-            let obj = match llama_core::audio::audio_translations(request).await {
-                Ok(obj) => obj,
-                Err(e) => {
-                    let err_msg = format!("Failed to translate the audio. {}", e);
+            let _lease = match lease {
+                Some(lease) => lease,
+                None => {
+                    let err_msg = "Failed to detect the language. The `file` field is required.";
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
+                    return error::bad_request(err_msg);
                 }
             };
 
-            // serialize chat completion object
-            let s = match serde_json::to_string(&obj) {
-                Ok(s) => s,
-                Err(e) => {
-                    let err_msg = format!("Failed to serialize transcription object. {}", e);
+            // wait for the whisper context
+            if let Err(response) = ticket.acquire().await {
+                return response;
+            }
 
+            let text = match inference::transcribe_opening(request).await {
+                Ok(text) => text,
+                Err(err_msg) => {
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
@@ -1187,13 +1221,15 @@ pub(crate) async fn whisper_translations_handler(req: Request<Body>) -> Response
                 }
             };
 
+            let body = serde_json::json!({ "text": text });
+
             // return response
             let result = Response::builder()
                 .header("Access-Control-Allow-Origin", "*")
                 .header("Access-Control-Allow-Methods", "*")
                 .header("Access-Control-Allow-Headers", "*")
                 .header("Content-Type", "application/json")
-                .body(Body::from(s));
+                .body(Body::from(body.to_string()));
 
             match result {
                 Ok(response) => response,
@@ -1217,11 +1253,409 @@ pub(crate) async fn whisper_translations_handler(req: Request<Body>) -> Response
         }
     };
 
-    info!(target: "stdout", "Send the audio translation response");
+    info!(target: "stdout", "Send the language detection response");
 
     res
 }
 
+/// Look up a file of the archive by id, or `None` if there is no such file.
+///
+/// A file missing from the working copy, e.g. uploaded to another instance sharing the file
+/// store, is fetched from the file store.
+async fn archived_file(file_id: &str) -> Option<FileObject> {
+    let record = index::get(file_id);
+
+    let path = record
+        .as_ref()
+        .map(|record| store::working_path(&record.id, &record.filename));
+    if !path.as_ref().is_some_and(|path| path.is_file()) {
+        match store::fetch(file_id).await {
+            Ok(filenames) if !filenames.is_empty() => (),
+            Ok(_) => {
+                // the record may outlive its file if the archive is edited by hand
+                if record.is_some() {
+                    warn!(target: "stdout", "The file {} is recorded in the file index, but missing on disk.", file_id);
+                }
+
+                return None;
+            }
+            Err(e) => {
+                error!(target: "stdout", "Failed to fetch the file {}. {}", file_id, e);
+
+                return None;
+            }
+        }
+    }
+
+    match record {
+        Some(record) => Some(record.file_object()),
+        None => index::recover(file_id)
+            .await
+            .map(|record| record.file_object()),
+    }
+}
+
+/// Resolve the `file_id` field of a transcription or translation request to its audio file.
+///
+/// If the audio pre-processor is enabled and the file is not a 16 kHz mono wav file, a converted
+/// copy is saved in a new directory of the archive, and the stored file is left untouched.
+#[allow(clippy::result_large_err)]
+async fn archived_audio_file(file_id: &str) -> Result<FileObject, Response<Body>> {
+    let file = match archived_file(file_id).await {
+        Some(file) => file,
+        None => {
+            let err_msg = format!("The file {} is not found.", file_id);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::not_found(err_msg));
+        }
+    };
+
+    // log
+    info!(target: "stdout", "file_id: {}, file_name: {}", &file.id, &file.filename);
+
+    let use_audio_preprocessor = match USE_AUDIO_PREPROCESSOR.get() {
+        Some(use_audio_preprocessor) => *use_audio_preprocessor,
+        None => false,
+    };
+    let path = store::working_path(&file.id, &file.filename);
+    match sniff::sniff_file(&path) {
+        Some(media_type) if media_type.audio => {
+            if audio::is_whisper_wav(&path) || (!use_audio_preprocessor && media_type.is_wav()) {
+                check_audio_duration(&file)?;
+
+                return Ok(file);
+            }
+        }
+        _ => {
+            let err_msg = format!("The file {} is not an audio file.", file_id);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::unsupported_media_type(err_msg));
+        }
+    }
+
+    // audio other than wav is only supported with the audio pre-processor
+    check_audio_file(&file.filename, &path)?;
+
+    // the converted copy goes to a new directory of the archive
+    let id = format!("file_{}", uuid::Uuid::new_v4());
+    let bytes = fs::metadata(&path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(file.bytes);
+    let result = match save_audio_file(&id, &file.filename, &path, bytes).await {
+        Ok(file) => check_audio_duration(&file).map(|_| file),
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            Err(error::internal_server_error(err_msg))
+        }
+    };
+    if result.is_err() {
+        discard_audio_file(&id);
+    }
+
+    result
+}
+
+/// Save an uploaded audio file in a new directory of the archive, as a 16 kHz wav file unless
+/// the audio pre-processor is disabled, and return its file object.
+///
+/// The file is written to disk as it arrives and converted from there, so that the upload is
+/// never held in memory. Content other than audio, or audio other than wav if the audio
+/// pre-processor is disabled, is refused with a 415.
+#[allow(clippy::result_large_err)]
+async fn archive_audio_file(
+    filename: &str,
+    field: Field<'_>,
+) -> Result<FileObject, Response<Body>> {
+    // create a unique file id
+    let id = format!("file_{}", uuid::Uuid::new_v4());
+    let dir = store::working_dir(&id);
+    if let Err(e) = fs::create_dir_all(&dir) {
+        let err_msg = format!("Failed to create the archive directory. {}", e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(error::internal_server_error(err_msg));
+    }
+    // keep the entry from being collected while the file is written and converted
+    let _lease = ArchiveLease::new(&id, false);
+
+    // the upload keeps a temporary name until it is checked and converted
+    let upload = dir.join(UPLOAD_FILENAME);
+    let result = match form::save_field(field, &upload).await {
+        Ok(bytes) => match check_audio_file(filename, &upload) {
+            Ok(()) => save_audio_file(&id, filename, &upload, bytes)
+                .await
+                .map_err(|err_msg| {
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }),
+            Err(response) => Err(response),
+        },
+        Err(response) => Err(response),
+    };
+    let result = result.and_then(|file| check_audio_duration(&file).map(|_| file));
+
+    match result {
+        Ok(_) => {
+            let _ = fs::remove_file(&upload);
+        }
+        Err(_) => discard_audio_file(&id),
+    }
+
+    result
+}
+
+// refuse content other than audio, or audio other than wav if the audio pre-processor is disabled
+#[allow(clippy::result_large_err)]
+fn check_audio_file(filename: &str, path: &Path) -> Result<(), Response<Body>> {
+    let use_audio_preprocessor = match USE_AUDIO_PREPROCESSOR.get() {
+        Some(use_audio_preprocessor) => *use_audio_preprocessor,
+        None => false,
+    };
+
+    match sniff::sniff_file(path) {
+        Some(media_type) if media_type.audio && !media_type.is_wav() && !use_audio_preprocessor => {
+            let err_msg = format!(
+                "The file {} is {} audio, which is only supported with the audio pre-processor. Send a wav file, or restart the server without `--no-audio-preprocessor`.",
+                filename, media_type.mime
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            Err(error::unsupported_media_type(err_msg))
+        }
+        Some(media_type) if media_type.audio => Ok(()),
+        _ => {
+            let err_msg = format!(
+                "The file {} is not a supported audio file. Supported formats are wav, mp3, aac, m4a, ogg, flac, webm and aiff.",
+                filename
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            Err(error::unsupported_media_type(err_msg))
+        }
+    }
+}
+
+// refuse audio longer than `--max-audio-duration`
+#[allow(clippy::result_large_err)]
+fn check_audio_duration(file: &FileObject) -> Result<(), Response<Body>> {
+    let max_audio_duration = match LIMITS.get().and_then(|limits| limits.max_audio_duration) {
+        Some(max_audio_duration) => max_audio_duration,
+        None => return Ok(()),
+    };
+
+    let path = store::working_path(&file.id, &file.filename);
+    match audio::wav_duration(&path) {
+        Some(duration) if duration > max_audio_duration => Err(error::invalid_request(
+            format!(
+                "The audio of {} lasts {:.1}s, which is longer than the limit of {}s.",
+                &file.filename, duration, max_audio_duration
+            ),
+            Some("file"),
+            "audio_too_long",
+        )),
+        _ => Ok(()),
+    }
+}
+
+// forget an archive entry created by a request which is refused
+fn discard_audio_file(file_id: &str) {
+    let _ = fs::remove_dir_all(store::working_dir(file_id));
+    index::remove(file_id);
+    store::remove_later(file_id);
+}
+
+// save the audio of `source` in the archive directory `id`, converted from the file if the audio
+// pre-processor is enabled, or else moved there; `bytes` is the size of the original audio
+async fn save_audio_file(
+    id: &str,
+    filename: &str,
+    source: &Path,
+    bytes: u64,
+) -> Result<FileObject, String> {
+    let original_filename = filename;
+
+    // create wav audio file to store the converted audio data
+    let file_path = store::working_dir(id);
+    if !file_path.exists() {
+        fs::create_dir_all(&file_path)
+            .map_err(|e| format!("Failed to create the archive directory. {}", e))?;
+    }
+    let output_wav_file = file_path.join(filename).with_extension("wav");
+    let filename = match output_wav_file.file_name() {
+        Some(filename) => filename.to_string_lossy().to_string(),
+        None => return Err(format!("Invalid filename: {}", filename)),
+    };
+
+    // log
+    info!(target: "stdout", "file_id: {}, file_name: {}", id, &filename);
+
+    let use_audio_preprocessor = match USE_AUDIO_PREPROCESSOR.get() {
+        Some(use_audio_preprocessor) => *use_audio_preprocessor,
+        None => false,
+    };
+
+    if use_audio_preprocessor && !audio::is_whisper_wav(source) {
+        info!(target: "stdout", "Pre-processing the audio file...");
+
+        // create a audio converter
+        let converter = wavup::AudioConverterBuilder::new(
+            output_wav_file.to_string_lossy(),
+            llama_core::metadata::whisper::WHISPER_SAMPLE_RATE as u32,
+        )
+        .build();
+
+        // convert to a wav audio file with the given sample rate
+        converter
+            .convert_audio_from_file(source)
+            .map_err(|e| format!("Failed to convert audio. {}", e))?;
+    } else {
+        // the audio is read by whisper as it is
+        fs::rename(source, &output_wav_file)
+            .map_err(|e| format!("Failed to write the audio data to the wav file. {}", e))?;
+    }
+
+    // keep the audio in the file store
+    store::save(id, &filename)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let created_at = utils::now();
+
+    // create a file object
+    let file = FileObject {
+        id: id.to_string(),
+        bytes,
+        created_at,
+        filename,
+        object: "file".to_string(),
+        purpose: "assistants".to_string(),
+    };
+    index::put(FileRecord::new(&file, original_filename, None)).await;
+
+    Ok(file)
+}
+
+// text of a transcript, from its segments or else its words
+fn transcript_text(segments: &Option<Vec<Segment>>, words: &Option<Vec<Word>>) -> String {
+    match (segments, words) {
+        (Some(segments), _) => transcript::plain_text(segments),
+        (None, Some(words)) => words
+            .iter()
+            .map(|word| word.word.as_str())
+            .collect::<Vec<&str>>()
+            .join(" "),
+        (None, None) => String::new(),
+    }
+}
+
+// duration of the audio, or the end of the last segment or word if it is not a wav file
+fn transcript_duration(
+    segments: &Option<Vec<Segment>>,
+    words: &Option<Vec<Word>>,
+    audio_file: impl AsRef<Path>,
+) -> f64 {
+    match audio::wav_duration(audio_file) {
+        Some(duration) => duration,
+        None => {
+            let segments_end = segments
+                .as_ref()
+                .and_then(|segments| segments.last())
+                .map(|segment| segment.end);
+            let words_end = words
+                .as_ref()
+                .and_then(|words| words.last())
+                .map(|word| word.end);
+
+            segments_end.or(words_end).unwrap_or_default()
+        }
+    }
+}
+
+/// Render the parsed whisper output in the requested response format.
+///
+/// `segments` is only `None` if word-level timestamps alone are requested for `verbose_json`.
+fn transcript_response(
+    segments: Option<Vec<Segment>>,
+    words: Option<Vec<Word>>,
+    speech_segments: Option<Vec<SpeechSegment>>,
+    response_format: ResponseFormat,
+    task: impl Into<String>,
+    language: Option<String>,
+    audio_file: impl AsRef<Path>,
+) -> Response<Body> {
+    let text = transcript_text(&segments, &words);
+
+    let body = match response_format {
+        ResponseFormat::Json => serde_json::to_string(&serde_json::json!({ "text": text })),
+        ResponseFormat::VerboseJson => {
+            let duration = transcript_duration(&segments, &words, audio_file);
+
+            serde_json::to_string(&VerboseTranscript {
+                task: task.into(),
+                language: language.filter(|language| language != "auto"),
+                duration,
+                text,
+                segments,
+                words,
+                speech_segments,
+            })
+        }
+        ResponseFormat::Text => Ok(text),
+        ResponseFormat::Srt => Ok(transcript::to_srt(&segments.unwrap_or_default())),
+        ResponseFormat::Vtt => Ok(transcript::to_vtt(&segments.unwrap_or_default())),
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => {
+            let err_msg = format!(
+                "Failed to serialize the {} response. {}",
+                response_format, e
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", response_format.content_type())
+        .body(Body::from(body));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
 pub(crate) async fn models_handler() -> Response<Body> {
     // log
     info!(target: "stdout", "Handling the coming model list request.");
@@ -1309,6 +1743,22 @@ pub(crate) async fn server_info_handler() -> Response<Body> {
         }
     };
 
+    // add the current state of the inference queue
+    let server_info = match serde_json::to_value(server_info) {
+        Ok(mut server_info) => {
+            server_info["queue"] = serde_json::json!(scheduler::status());
+            server_info
+        }
+        Err(e) => {
+            let err_msg = format!("Fail to serialize server info. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
     // serialize server info
     let s = match serde_json::to_string(&server_info) {
         Ok(s) => s,
@@ -1356,133 +1806,209 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
     info!(target: "stdout", "Handling the coming files request");
 
     let res = if req.method() == Method::POST {
-        let boundary = "boundary=";
+        let mut multipart = match form::multipart(req) {
+            Ok(multipart) => multipart,
+            Err(response) => return response,
+        };
+
+        let mut file_object: Option<FileObject> = None;
+        let mut purpose: Option<String> = None;
+        let mut owner: Option<String> = None;
+        // keeps the entry from being collected until it is recorded
+        let mut lease: Option<ArchiveLease> = None;
+        let parsed: Result<(), Response<Body>> = async {
+            loop {
+                let field = match form::next_field(&mut multipart).await {
+                    Ok(Some(field)) => field,
+                    Ok(None) => break,
+                    Err(response) => return Err(response),
+                };
+
+                match field.name().unwrap_or_default() {
+                    "file" if file_object.is_some() => {
+                        let err_msg =
+                            "Failed to upload the target file. The `file` field is repeated.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return Err(error::bad_request(err_msg));
+                    }
+                    "file" => {
+                        // keep the base name only, as the file is saved under this name
+                        let filename = match field
+                            .file_name()
+                            .map(Path::new)
+                            .and_then(Path::file_name)
+                        {
+                            Some(filename) => filename.to_string_lossy().to_string(),
+                            None => {
+                                let err_msg =
+                                    "Failed to upload the target file. The filename is not provided.";
+
+                                // log
+                                error!(target: "stdout", "{}", &err_msg);
+
+                                return Err(error::internal_server_error(err_msg));
+                            }
+                        };
+
+                        // create a unique file id
+                        let id = format!("file_{}", uuid::Uuid::new_v4());
+                        let dir = store::working_dir(&id);
+                        if let Err(e) = fs::create_dir_all(&dir) {
+                            let err_msg =
+                                format!("Failed to create the archive directory. {}", e);
 
-        let boundary = req.headers().get("content-type").and_then(|ct| {
-            let ct = ct.to_str().ok()?;
-            let idx = ct.find(boundary)?;
-            Some(ct[idx + boundary.len()..].to_string())
-        });
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
+
+                            return Err(error::internal_server_error(err_msg));
+                        }
+                        // keep the entry from being collected while the file is written
+                        lease = Some(ArchiveLease::new(&id, false));
+
+                        // write the file as it arrives
+                        let path = dir.join(&filename);
+                        let size_in_bytes = match form::save_field(field, &path).await {
+                            Ok(size_in_bytes) => size_in_bytes,
+                            Err(response) => {
+                                let _ = fs::remove_dir_all(&dir);
+
+                                return Err(response);
+                            }
+                        };
 
-        let req_body = req.into_body();
-        let body_bytes = match to_bytes(req_body).await {
-            Ok(body_bytes) => body_bytes,
-            Err(e) => {
-                let err_msg = format!("Fail to read buffer from request body. {}", e);
+                        // classify the file by its content, whatever its extension
+                        if sniff::sniff_file(&path).is_none() {
+                            let _ = fs::remove_dir_all(&dir);
+                            let err_msg = format!(
+                                "Failed to upload the target file. The content of {} is not supported. Supported files are audio (wav, mp3, aac, m4a, ogg, flac, webm, aiff), png images and UTF-8 text.",
+                                &filename
+                            );
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                return error::internal_server_error(err_msg);
-            }
-        };
+                            return Err(error::unsupported_media_type(err_msg));
+                        }
 
-        let cursor = Cursor::new(body_bytes.to_vec());
+                        // keep the file in the file store
+                        if let Err(e) = store::save(&id, &filename).await {
+                            let _ = fs::remove_dir_all(&dir);
+                            let err_msg = e.to_string();
 
-        let mut multipart = Multipart::with_body(cursor, boundary.unwrap());
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-        let mut file_object: Option<FileObject> = None;
-        while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
-            if &*field.headers.name == "file" {
-                let filename = match field.headers.filename {
-                    Some(filename) => filename,
-                    None => {
-                        let err_msg =
-                            "Failed to upload the target file. The filename is not provided.";
+                            return Err(error::internal_server_error(err_msg));
+                        }
 
                         // log
-                        error!(target: "stdout", "{}", &err_msg);
+                        info!(target: "stdout", "file_id: {}, file_name: {}", &id, &filename);
 
-                        return error::internal_server_error(err_msg);
-                    }
-                };
+                        let created_at = match SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                        {
+                            Ok(n) => n.as_secs(),
+                            Err(_) => {
+                                let err_msg = "Failed to get the current time.";
 
-                if !((filename).to_lowercase().ends_with(".txt")
-                    || (filename).to_lowercase().ends_with(".md")
-                    || (filename).to_lowercase().ends_with(".png")
-                    || (filename).to_lowercase().ends_with(".wav"))
-                {
-                    let err_msg = format!(
-                        "Failed to upload the target file. Only files with 'txt', 'md', 'png', 'wav' extensions are supported. The file to be uploaded is {}.",
-                        &filename
-                    );
+                                // log
+                                error!(target: "stdout", "{}", &err_msg);
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+                                return Err(error::internal_server_error(err_msg));
+                            }
+                        };
 
-                    return error::internal_server_error(err_msg);
-                }
+                        // create a file object
+                        file_object = Some(FileObject {
+                            id,
+                            bytes: size_in_bytes,
+                            created_at,
+                            filename,
+                            object: "file".to_string(),
+                            purpose: "assistants".to_string(),
+                        });
+                    }
+                    "purpose" => match form::is_text(&field) {
+                        true => {
+                            let value = match field.text().await {
+                                Ok(value) => value,
+                                Err(e) => {
+                                    let err_msg = format!("Failed to read `purpose`. {}", e);
 
-                let mut buffer = Vec::new();
-                let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                    Ok(size_in_bytes) => size_in_bytes,
-                    Err(e) => {
-                        let err_msg = format!("Failed to read the target file. {}", e);
+                                    // log
+                                    error!(target: "stdout", "{}", &err_msg);
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+                                    return Err(error::internal_server_error(err_msg));
+                                }
+                            };
 
-                        return error::internal_server_error(err_msg);
-                    }
-                };
+                            purpose = Some(value.trim().to_string());
+                        }
+                        false => {
+                            let err_msg =
+                                "Failed to get `purpose`. The `purpose` field in the request should be a text field.";
 
-                // create a unique file id
-                let id = format!("file_{}", uuid::Uuid::new_v4());
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                // save the file
-                let path = Path::new("archives");
-                if !path.exists() {
-                    fs::create_dir(path).unwrap();
-                }
-                let file_path = path.join(&id);
-                if !file_path.exists() {
-                    fs::create_dir(&file_path).unwrap();
-                }
-                let mut file = match File::create(file_path.join(&filename)) {
-                    Ok(file) => file,
-                    Err(e) => {
-                        let err_msg =
-                            format!("Failed to create archive document {}. {}", &filename, e);
+                            return Err(error::internal_server_error(err_msg));
+                        }
+                    },
+                    "user" => match form::is_text(&field) {
+                        true => {
+                            let value = match field.text().await {
+                                Ok(value) => value,
+                                Err(e) => {
+                                    let err_msg = format!("Failed to read `user`. {}", e);
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+                                    // log
+                                    error!(target: "stdout", "{}", &err_msg);
 
-                        return error::internal_server_error(err_msg);
-                    }
-                };
-                file.write_all(&buffer[..]).unwrap();
+                                    return Err(error::internal_server_error(err_msg));
+                                }
+                            };
 
-                // log
-                info!(target: "stdout", "file_id: {}, file_name: {}", &id, &filename);
+                            owner = Some(value.trim().to_string());
+                        }
+                        false => {
+                            let err_msg =
+                                "Failed to get `user`. The `user` field in the request should be a text field.";
 
-                let created_at = match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                    Ok(n) => n.as_secs(),
-                    Err(_) => {
-                        let err_msg = "Failed to get the current time.";
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+                            return Err(error::internal_server_error(err_msg));
+                        }
+                    },
+                    _ => (),
+                }
+            }
 
-                        return error::internal_server_error(err_msg);
-                    }
-                };
+            Ok(())
+        }
+        .await;
 
-                // create a file object
-                file_object = Some(FileObject {
-                    id,
-                    bytes: size_in_bytes as u64,
-                    created_at,
-                    filename,
-                    object: "file".to_string(),
-                    purpose: "assistants".to_string(),
-                });
-
-                break;
+        // a file saved before an invalid field is not kept
+        if let Err(response) = parsed {
+            if let Some(fo) = &file_object {
+                let _ = fs::remove_dir_all(store::working_dir(&fo.id));
+                store::remove_later(&fo.id);
             }
+
+            return response;
         }
 
         match file_object {
-            Some(fo) => {
+            Some(mut fo) => {
+                if let Some(purpose) = purpose.filter(|purpose| !purpose.is_empty()) {
+                    fo.purpose = purpose;
+                }
+                index::put(FileRecord::new(&fo, fo.filename.clone(), owner)).await;
+                drop(lease);
+
                 // serialize chat completion object
                 let s = match serde_json::to_string(&fo) {
                     Ok(s) => s,
@@ -1526,39 +2052,69 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
             }
         }
     } else if req.method() == Method::GET {
-        let uri_path = req.uri().path().trim_end_matches('/').to_lowercase();
+        let uri_path = req.uri().path().trim_end_matches('/').to_string();
 
         // Split the path into segments
         let segments: Vec<&str> = uri_path.split('/').collect();
 
         match segments.as_slice() {
-            ["", "v1", "files"] => list_files(),
+            ["", "v1", "files"] => list_files(req.uri().query()).await,
+            ["", "v1", "files", id] => retrieve_file(id).await,
+            ["", "v1", "files", id, "content"] => retrieve_file_content(id).await,
             _ => {
                 let err_msg = format!("unsupported uri path: {}", uri_path);
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                error::internal_server_error(err_msg)
+                error::invalid_endpoint(err_msg)
             }
         }
     } else if req.method() == Method::DELETE {
         let id = req.uri().path().trim_start_matches("/v1/files/");
-        let status = match llama_core::files::remove_file(id) {
-            Ok(status) => status,
-            Err(e) => {
-                let err_msg = format!("Failed to delete the target file with id {}. {}", id, e);
+        let deleted = match archived_file(id).await {
+            Some(file_object) => {
+                if let Err(e) = store::remove(&file_object.id).await {
+                    let err_msg = format!("Failed to delete the target file with id {}. {}", id, e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+                }
+
+                match fs::remove_dir_all(store::working_dir(&file_object.id)) {
+                    Ok(_) => {
+                        index::remove(&file_object.id);
+
+                        true
+                    }
+                    Err(e) => {
+                        let err_msg =
+                            format!("Failed to delete the target file with id {}. {}", id, e);
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        false
+                    }
+                }
+            }
+            None => {
+                let err_msg = format!(
+                    "Failed to delete the target file with id {}. The file is not found.",
+                    id
+                );
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                DeleteFileStatus {
-                    id: id.into(),
-                    object: "file".to_string(),
-                    deleted: false,
-                }
+                false
             }
         };
+        let status = DeleteFileStatus {
+            id: id.into(),
+            object: "file".to_string(),
+            deleted,
+        };
 
         // serialize status
         let s = match serde_json::to_string(&status) {
@@ -1628,44 +2184,262 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
     res
 }
 
-fn list_files() -> Response<Body> {
-    match llama_core::files::list_files() {
-        Ok(file_objects) => {
-            // serialize chat completion object
-            let s = match serde_json::to_string(&file_objects) {
-                Ok(s) => s,
-                Err(e) => {
-                    let err_msg = format!("Failed to serialize file list. {}", e);
+async fn retrieve_file(id: &str) -> Response<Body> {
+    let file_object = match archived_file(id).await {
+        Some(file_object) => file_object,
+        None => return error::not_found(format!("The file {} is not found.", id)),
+    };
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    // serialize file object
+    let s = match serde_json::to_string(&file_object) {
+        Ok(s) => s,
+        Err(e) => {
+            let err_msg = format!("Failed to serialize file object. {}", e);
 
-                    return error::internal_server_error(err_msg);
-                }
-            };
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .body(Body::from(s));
+            return error::internal_server_error(err_msg);
+        }
+    };
 
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(s));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+async fn retrieve_file_content(id: &str) -> Response<Body> {
+    let file_object = match archived_file(id).await {
+        Some(file_object) => file_object,
+        None => return error::not_found(format!("The file {} is not found.", id)),
+    };
+
+    let path = store::working_path(&file_object.id, &file_object.filename);
+    let content_type = match index::get(&file_object.id).and_then(|record| record.mime_type) {
+        Some(mime_type) => mime_type,
+        None => content_type(&file_object.filename).to_string(),
+    };
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            let err_msg = format!("Failed to open the file {}. {}", id, e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+    // the length of the file streamed, which the record may not match
+    let content_length = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            let err_msg = format!("Failed to read the metadata of the file {}. {}", id, e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
 
+    // stream the stored bytes instead of loading the whole file in memory
+    let (mut sender, body) = Body::channel();
+    let file_id = file_object.id.clone();
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            match tokio::io::AsyncReadExt::read(&mut file, &mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender
+                        .send_data(hyper::body::Bytes::copy_from_slice(&buffer[..n]))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => {
                     // log
-                    error!(target: "stdout", "{}", &err_msg);
+                    error!(target: "stdout", "Failed to read the file {}. {}", &file_id, e);
 
-                    error::internal_server_error(err_msg)
+                    sender.abort();
+                    break;
                 }
             }
         }
+    });
+
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", content_type)
+        .header("Content-Length", content_length)
+        .body(body);
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+// content type of a stored file, from its extension
+fn content_type(filename: &str) -> &'static str {
+    let extension = Path::new(filename)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "wav" => "audio/wav",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "png" => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A page of `GET /v1/files`.
+#[derive(Debug, Serialize)]
+struct FileList {
+    object: String,
+    data: Vec<FileObject>,
+    first_id: Option<String>,
+    last_id: Option<String>,
+    has_more: bool,
+}
+
+// list the archived files, `limit` at a time, with the `after`, `order` and `purpose` query
+// parameters
+async fn list_files(query: Option<&str>) -> Response<Body> {
+    let params = utils::query_params(query);
+
+    let limit = match params.get("limit") {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if (1..=MAX_LIST_LIMIT).contains(&limit) => limit,
+            _ => {
+                let err_msg = format!(
+                    "Invalid limit: {}. The limit must be between 1 and {}.",
+                    limit, MAX_LIST_LIMIT
+                );
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::bad_request(err_msg);
+            }
+        },
+        None => MAX_LIST_LIMIT,
+    };
+
+    let order = match params.get("order").map(|order| order.as_str()) {
+        Some("asc") => SortOrder::Asc,
+        Some("desc") | None => SortOrder::Desc,
+        Some(order) => {
+            let err_msg = format!(
+                "Invalid order: {}. Supported values are `asc` and `desc`.",
+                order
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    let purpose = params
+        .get("purpose")
+        .map(|purpose| purpose.as_str())
+        .filter(|purpose| !purpose.is_empty());
+    let after = params
+        .get("after")
+        .map(|after| after.as_str())
+        .filter(|after| !after.is_empty());
+
+    // the files uploaded to other instances sharing the file store are listed too
+    if let Err(e) = index::sync().await {
+        error!(target: "stdout", "Failed to sync the file index with the file store. {}", e);
+    }
+
+    let page = match index::page(purpose, order, after, limit) {
+        Some(page) => page,
+        None => {
+            let err_msg = format!(
+                "Invalid after: {}. The file is not found.",
+                after.unwrap_or_default()
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    let file_objects = FileList {
+        object: "list".to_string(),
+        first_id: page.records.first().map(|record| record.id.clone()),
+        last_id: page.records.last().map(|record| record.id.clone()),
+        data: page
+            .records
+            .iter()
+            .map(|record| record.file_object())
+            .collect(),
+        has_more: page.has_more,
+    };
+
+    // serialize chat completion object
+    let s = match serde_json::to_string(&file_objects) {
+        Ok(s) => s,
+        Err(e) => {
+            let err_msg = format!("Failed to serialize file list. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(s));
+
+    match result {
+        Ok(response) => response,
         Err(e) => {
-            let err_msg = format!("Failed to list all files. {}", e);
+            let err_msg = e.to_string();
 
             // log
             error!(target: "stdout", "{}", &err_msg);
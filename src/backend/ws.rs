@@ -0,0 +1,374 @@
+use super::{
+    inference::AudioRequest,
+    scheduler::{self, Session},
+};
+use crate::{
+    archive::ArchiveLease,
+    audio::WavAudio,
+    error, store,
+    transcript::{self, Segment},
+    utils,
+};
+use endpoints::{audio::transcription::TranscriptionRequest, files::FileObject};
+use futures::{SinkExt, StreamExt};
+use hyper::{upgrade::Upgraded, Body, Method, Request, Response, StatusCode};
+use serde_json::json;
+use std::{fs, path::PathBuf};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+// default sample rate of the incoming PCM16 frames
+const DEFAULT_SAMPLE_RATE: u32 = 16000;
+// a partial hypothesis is sent each time this much new audio is received, in seconds
+const PARTIAL_INTERVAL_SECONDS: f64 = 2.0;
+// the uncommitted audio is finalized once it grows beyond this length, in seconds
+const MAX_WINDOW_SECONDS: f64 = 15.0;
+
+/// Upgrade `GET /v1/audio/stream` to a WebSocket for realtime transcription.
+///
+/// The client sends binary frames of mono little-endian PCM16 audio, at the rate given by the
+/// `sample_rate` query parameter (16000 by default). `language` and `prompt` are also read from
+/// the query string. The server replies with JSON text messages:
+///
+/// - `{"type": "partial", "text", "start", "end"}`: hypothesis for the audio not finalized yet;
+/// - `{"type": "final", "text", "start", "end", "segments"}`: transcript of a finalized window;
+/// - `{"type": "error", "error": {"message"}}`.
+///
+/// The client may send `{"type": "flush"}` to finalize the buffered audio right away. Closing the
+/// socket finalizes the remaining audio too.
+pub(crate) async fn audio_stream_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio stream request");
+
+    if req.method() != Method::GET {
+        let err_msg = "Invalid HTTP Method.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    let accept_key = match websocket_accept_key(&req) {
+        Some(accept_key) => accept_key,
+        None => {
+            let err_msg =
+                "Failed to upgrade the connection. The request is not a WebSocket handshake.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    let params = utils::query_params(req.uri().query());
+    let sample_rate = match params.get("sample_rate") {
+        Some(sample_rate) => match sample_rate.parse::<u32>() {
+            Ok(sample_rate) if sample_rate > 0 => sample_rate,
+            _ => {
+                let err_msg = format!("Invalid `sample_rate`: {}", sample_rate);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::bad_request(err_msg);
+            }
+        },
+        None => DEFAULT_SAMPLE_RATE,
+    };
+
+    // the number of live sessions is bounded, as each may wait for the whisper context
+    let live = match scheduler::open_session() {
+        Ok(live) => live,
+        Err(response) => return response,
+    };
+    let session = StreamSession::new(
+        sample_rate,
+        params.get("language").cloned(),
+        params.get("prompt").cloned(),
+        live,
+    );
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                run_session(ws, session).await;
+            }
+            Err(e) => {
+                let err_msg = format!("Failed to upgrade the connection. {}", e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+            }
+        }
+    });
+
+    let result = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Accept", accept_key)
+        .body(Body::empty());
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// Compute `Sec-WebSocket-Accept`, or `None` if the request is not a WebSocket handshake.
+pub(crate) fn websocket_accept_key(req: &Request<Body>) -> Option<String> {
+    let upgrade = req.headers().get("upgrade")?.to_str().ok()?;
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return None;
+    }
+
+    let key = req.headers().get("sec-websocket-key")?;
+
+    Some(derive_accept_key(key.as_bytes()))
+}
+
+// buffered audio of a streaming session
+struct StreamSession {
+    sample_rate: u32,
+    language: Option<String>,
+    prompt: Option<String>,
+    file_id: String,
+    dir: PathBuf,
+    // keeps the audio of the stream from being collected
+    _lease: ArchiveLease,
+    live: Session,
+    // audio received but not finalized yet
+    samples: Vec<f32>,
+    // trailing byte of an odd-sized frame
+    remainder: Option<u8>,
+    // start of the uncommitted audio in the stream, in seconds
+    committed: f64,
+    // number of samples received since the last hypothesis
+    since_hypothesis: usize,
+}
+impl StreamSession {
+    fn new(
+        sample_rate: u32,
+        language: Option<String>,
+        prompt: Option<String>,
+        live: Session,
+    ) -> Self {
+        let file_id = format!("file_{}", uuid::Uuid::new_v4());
+        let dir = store::working_dir(&file_id);
+
+        Self {
+            sample_rate,
+            language,
+            prompt,
+            _lease: ArchiveLease::new(&file_id, false),
+            live,
+            file_id,
+            dir,
+            samples: Vec::new(),
+            remainder: None,
+            committed: 0.0,
+            since_hypothesis: 0,
+        }
+    }
+
+    fn push_pcm16(&mut self, data: &[u8]) {
+        let mut bytes = Vec::with_capacity(data.len() + 1);
+        if let Some(byte) = self.remainder.take() {
+            bytes.push(byte);
+        }
+        bytes.extend_from_slice(data);
+        if bytes.len() % 2 == 1 {
+            self.remainder = bytes.pop();
+        }
+
+        let audio = WavAudio::from_pcm16(&bytes, self.sample_rate);
+        self.since_hypothesis += audio.samples.len();
+        self.samples.extend(audio.samples);
+    }
+
+    fn buffered_seconds(&self) -> f64 {
+        self.samples.len() as f64 / self.sample_rate as f64
+    }
+
+    fn needs_partial(&self) -> bool {
+        self.since_hypothesis as f64 / self.sample_rate as f64 >= PARTIAL_INTERVAL_SECONDS
+    }
+
+    // transcribe the uncommitted audio; the times of the segments are relative to it
+    async fn transcribe(&mut self) -> Result<Vec<Segment>, String> {
+        self.since_hypothesis = 0;
+
+        transcribe_samples(
+            &self.live,
+            &self.file_id,
+            WavAudio::from_samples(self.samples.clone(), self.sample_rate),
+            self.language.clone(),
+            self.prompt.clone(),
+        )
+        .await
+    }
+
+    async fn partial(&mut self) -> Result<serde_json::Value, String> {
+        let segments = self.transcribe().await?;
+
+        Ok(json!({
+            "type": "partial",
+            "text": transcript::plain_text(&segments),
+            "start": self.committed,
+            "end": self.committed + self.buffered_seconds(),
+        }))
+    }
+
+    async fn finalize(&mut self) -> Result<Option<serde_json::Value>, String> {
+        if self.samples.is_empty() {
+            return Ok(None);
+        }
+
+        let segments = self.transcribe().await?;
+        let start = self.committed;
+        let end = self.committed + self.buffered_seconds();
+
+        self.samples.clear();
+        self.committed = end;
+
+        let segments: Vec<Segment> = segments
+            .into_iter()
+            .map(|mut segment| {
+                segment.start += start;
+                segment.end += start;
+                segment
+            })
+            .collect();
+
+        Ok(Some(json!({
+            "type": "final",
+            "text": transcript::plain_text(&segments),
+            "start": start,
+            "end": end,
+            "segments": segments,
+        })))
+    }
+}
+
+async fn run_session(mut ws: WebSocketStream<Upgraded>, mut session: StreamSession) {
+    info!(target: "stdout", "Start the audio stream {}", &session.file_id);
+
+    while let Some(message) = ws.next().await {
+        let reply = match message {
+            Ok(Message::Binary(data)) => {
+                session.push_pcm16(&data);
+
+                if session.buffered_seconds() >= MAX_WINDOW_SECONDS {
+                    session.finalize().await
+                } else if session.needs_partial() {
+                    session.partial().await.map(Some)
+                } else {
+                    Ok(None)
+                }
+            }
+            Ok(Message::Text(text)) => {
+                let command: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+                match command.get("type").and_then(|ty| ty.as_str()) {
+                    Some("flush") => session.finalize().await,
+                    _ => Err(format!("Unsupported message: {}", text)),
+                }
+            }
+            Ok(Message::Close(_)) => {
+                if let Ok(Some(event)) = session.finalize().await {
+                    let _ = ws.send(Message::Text(event.to_string())).await;
+                }
+                break;
+            }
+            Ok(_) => Ok(None),
+            Err(e) => {
+                // log
+                error!(target: "stdout", "Failed to read from the audio stream {}. {}", &session.file_id, e);
+
+                break;
+            }
+        };
+
+        let event = match reply {
+            Ok(Some(event)) => event,
+            Ok(None) => continue,
+            Err(err_msg) => {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                json!({"type": "error", "error": {"message": err_msg}})
+            }
+        };
+
+        if let Err(e) = ws.send(Message::Text(event.to_string())).await {
+            // log
+            error!(target: "stdout", "Failed to write to the audio stream {}. {}", &session.file_id, e);
+
+            break;
+        }
+    }
+
+    // the audio of a stream is not kept
+    if session.dir.exists() {
+        let _ = fs::remove_dir_all(&session.dir);
+    }
+
+    info!(target: "stdout", "Close the audio stream {}", &session.file_id);
+}
+
+/// Convert the buffered audio of a streaming session to a 16 kHz wav file in the archive
+/// directory of the session, and transcribe it.
+pub(crate) async fn transcribe_samples(
+    live: &Session,
+    file_id: &str,
+    audio: WavAudio,
+    language: Option<String>,
+    prompt: Option<String>,
+) -> Result<Vec<Segment>, String> {
+    let dir = store::working_dir(file_id);
+    if !dir.exists() {
+        fs::create_dir_all(&dir).map_err(|e| {
+            format!(
+                "Failed to create the archive directory of the stream. {}",
+                e
+            )
+        })?;
+    }
+
+    let filename = "window.wav";
+    audio
+        .save_for_whisper(dir.join(filename))
+        .map_err(|e| e.to_string())?;
+
+    let created_at = utils::now();
+    let request = AudioRequest::Transcription(TranscriptionRequest {
+        file: FileObject {
+            id: file_id.to_string(),
+            bytes: (audio.samples.len() * 2) as u64,
+            created_at,
+            filename: filename.to_string(),
+            object: "file".to_string(),
+            purpose: "assistants".to_string(),
+        },
+        language,
+        prompt,
+        ..Default::default()
+    });
+
+    // live audio waits for the whisper context outside of the bound of the queue, as the
+    // sessions have a bound of their own
+    let _ticket = live.ticket().await;
+
+    request.run().await.map(transcript::parse_segments)
+}
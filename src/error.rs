@@ -90,6 +90,134 @@ pub(crate) fn invalid_endpoint(msg: impl AsRef<str>) -> Response<Body> {
         .unwrap()
 }
 
+pub(crate) fn not_found(msg: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "404 Not Found".to_string(),
+        false => format!("404 Not Found: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .status(hyper::StatusCode::NOT_FOUND)
+        .body(Body::from(err_msg))
+        .unwrap()
+}
+
+pub(crate) fn unsupported_media_type(msg: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "415 Unsupported Media Type".to_string(),
+        false => format!("415 Unsupported Media Type: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .status(hyper::StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .body(Body::from(err_msg))
+        .unwrap()
+}
+
+/// A 413 with an error object in the format of the OpenAI API. `param` names the field which is
+/// too large, if any.
+pub(crate) fn payload_too_large(msg: impl AsRef<str>, param: Option<&str>) -> Response<Body> {
+    api_error(
+        hyper::StatusCode::PAYLOAD_TOO_LARGE,
+        msg.as_ref(),
+        "invalid_request_error",
+        param,
+        "payload_too_large",
+    )
+}
+
+/// A 400 with an error object in the format of the OpenAI API, for a request which is well formed
+/// but refused, e.g. audio over the duration limit.
+pub(crate) fn invalid_request(
+    msg: impl AsRef<str>,
+    param: Option<&str>,
+    code: &str,
+) -> Response<Body> {
+    api_error(
+        hyper::StatusCode::BAD_REQUEST,
+        msg.as_ref(),
+        "invalid_request_error",
+        param,
+        code,
+    )
+}
+
+/// A 429 with an error object in the format of the OpenAI API, telling the client to retry after
+/// `retry_after` seconds.
+pub(crate) fn too_many_requests(msg: impl AsRef<str>, retry_after: u64) -> Response<Body> {
+    let mut response = api_error(
+        hyper::StatusCode::TOO_MANY_REQUESTS,
+        msg.as_ref(),
+        "requests",
+        None,
+        "rate_limit_exceeded",
+    );
+    response
+        .headers_mut()
+        .insert(hyper::header::RETRY_AFTER, retry_after.into());
+
+    response
+}
+
+/// A 503 with an error object in the format of the OpenAI API, telling the client to retry after
+/// `retry_after` seconds.
+pub(crate) fn service_unavailable(msg: impl AsRef<str>, retry_after: u64) -> Response<Body> {
+    let mut response = api_error(
+        hyper::StatusCode::SERVICE_UNAVAILABLE,
+        msg.as_ref(),
+        "server_error",
+        None,
+        "service_unavailable",
+    );
+    response
+        .headers_mut()
+        .insert(hyper::header::RETRY_AFTER, retry_after.into());
+
+    response
+}
+
+// `{"error": {"message", "type", "param", "code"}}`, as returned by the OpenAI API
+fn api_error(
+    status: hyper::StatusCode,
+    msg: &str,
+    err_type: &str,
+    param: Option<&str>,
+    code: &str,
+) -> Response<Body> {
+    // log error
+    error!(target: "stdout", "{} {}", status.as_u16(), msg);
+
+    let body = serde_json::json!({
+        "error": {
+            "message": msg,
+            "type": err_type,
+            "param": param,
+            "code": code,
+        }
+    });
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .status(status)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
 #[derive(Error, Clone, Debug, PartialEq, Eq)]
 pub enum ServerError {
     /// Generic error returned while performing an operation
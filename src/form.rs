@@ -0,0 +1,218 @@
+use crate::{error, LIMITS};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Request, Response,
+};
+use multer::{Constraints, Field, Multipart, SizeLimit};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::Write, path::Path};
+
+/// Limits on the request bodies and on the input audio. `None` means no limit.
+///
+/// The sizes are enforced while the body is read, so that a request over a limit is refused
+/// before it is written to disk.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct Limits {
+    /// Maximum size of a request body, in bytes.
+    pub(crate) max_request_bytes: Option<u64>,
+    /// Maximum size of a field of a multipart body, e.g. the `file` field, in bytes.
+    pub(crate) max_file_bytes: Option<u64>,
+    /// Maximum duration of the input audio once decoded, in seconds.
+    pub(crate) max_audio_duration: Option<f64>,
+}
+
+/// Start parsing the `multipart/form-data` body of a request.
+///
+/// The fields are read from the body as they arrive, so that a large file is never held in
+/// memory as a whole. A body announced larger than `--max-request-bytes` is refused with a 413
+/// without reading it.
+#[allow(clippy::result_large_err)]
+pub(crate) fn multipart(req: Request<Body>) -> Result<Multipart<'static>, Response<Body>> {
+    let limits = LIMITS.get().copied().unwrap_or_default();
+    check_content_length(&req, limits.max_request_bytes)?;
+
+    let boundary = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .and_then(|ct| multer::parse_boundary(ct).ok());
+
+    let mut size_limit = SizeLimit::new();
+    if let Some(max_request_bytes) = limits.max_request_bytes {
+        size_limit = size_limit.whole_stream(max_request_bytes);
+    }
+    if let Some(max_file_bytes) = limits.max_file_bytes {
+        size_limit = size_limit.per_field(max_file_bytes);
+    }
+
+    match boundary {
+        Some(boundary) => Ok(Multipart::with_constraints(
+            req.into_body(),
+            boundary,
+            Constraints::new().size_limit(size_limit),
+        )),
+        None => {
+            let err_msg = "Failed to get the boundary from the request. The request body should be multipart/form-data.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            Err(error::bad_request(err_msg))
+        }
+    }
+}
+
+/// Read the whole body of a request, e.g. a JSON body, refusing it with a 413 as soon as it is
+/// larger than `--max-request-bytes`.
+pub(crate) async fn read_body(req: Request<Body>) -> Result<Bytes, Response<Body>> {
+    let max_request_bytes = LIMITS.get().and_then(|limits| limits.max_request_bytes);
+    check_content_length(&req, max_request_bytes)?;
+
+    let mut body = req.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(error::internal_server_error(err_msg));
+            }
+        };
+
+        bytes.extend_from_slice(&chunk);
+        if let Some(max_request_bytes) = max_request_bytes {
+            if bytes.len() as u64 > max_request_bytes {
+                return Err(error::payload_too_large(
+                    format!(
+                        "The request body is larger than the limit of {} bytes.",
+                        max_request_bytes
+                    ),
+                    None,
+                ));
+            }
+        }
+    }
+
+    Ok(Bytes::from(bytes))
+}
+
+// refuse a body announced larger than the limit without reading it
+#[allow(clippy::result_large_err)]
+fn check_content_length(
+    req: &Request<Body>,
+    max_request_bytes: Option<u64>,
+) -> Result<(), Response<Body>> {
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if let (Some(content_length), Some(max_request_bytes)) = (content_length, max_request_bytes) {
+        if content_length > max_request_bytes {
+            return Err(error::payload_too_large(
+                format!(
+                    "The request body of {} bytes is larger than the limit of {} bytes.",
+                    content_length, max_request_bytes
+                ),
+                None,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The next field of a multipart body, or `None` after the last one.
+///
+/// The previous field must be dropped before reading the next one.
+#[allow(clippy::result_large_err)]
+pub(crate) async fn next_field(
+    multipart: &mut Multipart<'static>,
+) -> Result<Option<Field<'static>>, Response<Body>> {
+    multipart.next_field().await.map_err(read_error)
+}
+
+/// Whether a field is a text field, i.e. it has no content type or a `text/*` one.
+pub(crate) fn is_text(field: &Field) -> bool {
+    field
+        .content_type()
+        .is_none_or(|content_type| content_type.type_() == "text")
+}
+
+/// Write the content of a field to a file as it arrives, and return its size in bytes.
+///
+/// The file is removed if the field cannot be read to the end, e.g. because it is over a limit.
+#[allow(clippy::result_large_err)]
+pub(crate) async fn save_field(mut field: Field<'_>, path: &Path) -> Result<u64, Response<Body>> {
+    let mut file = match File::create(path) {
+        Ok(file) => file,
+        Err(e) => {
+            let err_msg = format!("Failed to create {}. {}", path.display(), e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::internal_server_error(err_msg));
+        }
+    };
+
+    let mut bytes = 0;
+    loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                let _ = std::fs::remove_file(path);
+
+                return Err(read_error(e));
+            }
+        };
+
+        if let Err(e) = file.write_all(&chunk) {
+            let _ = std::fs::remove_file(path);
+            let err_msg = format!("Failed to write {}. {}", path.display(), e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::internal_server_error(err_msg));
+        }
+        bytes += chunk.len() as u64;
+    }
+
+    Ok(bytes)
+}
+
+// a 413 for a body or a field over its limit, or else a 400 for a malformed body
+fn read_error(e: multer::Error) -> Response<Body> {
+    match e {
+        multer::Error::StreamSizeExceeded { limit } => error::payload_too_large(
+            format!(
+                "The request body is larger than the limit of {} bytes.",
+                limit
+            ),
+            None,
+        ),
+        multer::Error::FieldSizeExceeded { limit, field_name } => error::payload_too_large(
+            format!(
+                "The `{}` field is larger than the limit of {} bytes.",
+                field_name.as_deref().unwrap_or_default(),
+                limit
+            ),
+            field_name.as_deref(),
+        ),
+        e => {
+            let err_msg = format!("Failed to read the multipart body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::bad_request(err_msg)
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use crate::{audio, error::ServerError, sniff, store};
+use endpoints::files::FileObject;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+// name of the index file in the root of the working copy
+const INDEX_FILENAME: &str = "index.jsonl";
+
+// index of the archived files
+static FILE_INDEX: OnceCell<Mutex<FileIndex>> = OnceCell::new();
+
+/// Metadata of an archived file, as recorded in the index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FileRecord {
+    pub(crate) id: String,
+    /// Name of the stored file in the directory `{id}` of the store.
+    pub(crate) filename: String,
+    /// Name of the file as uploaded.
+    pub(crate) original_filename: String,
+    /// Size of the upload, in bytes.
+    pub(crate) bytes: u64,
+    /// Size of the stored file, after conversion, in bytes.
+    pub(crate) stored_bytes: u64,
+    pub(crate) created_at: u64,
+    pub(crate) purpose: String,
+    #[serde(default)]
+    pub(crate) owner: Option<String>,
+    /// Duration of the audio in seconds, for wav files.
+    #[serde(default)]
+    pub(crate) duration: Option<f64>,
+    /// Sample rate of the audio, for wav files.
+    #[serde(default)]
+    pub(crate) sample_rate: Option<u32>,
+    /// Media type of the stored file, detected from its content.
+    #[serde(default)]
+    pub(crate) mime_type: Option<String>,
+}
+impl FileRecord {
+    /// Create the record of a stored file, reading its size and audio format from the disk.
+    pub(crate) fn new(
+        file: &FileObject,
+        original_filename: impl Into<String>,
+        owner: Option<String>,
+    ) -> Self {
+        let path = store::working_path(&file.id, &file.filename);
+
+        Self {
+            id: file.id.clone(),
+            filename: file.filename.clone(),
+            original_filename: original_filename.into(),
+            bytes: file.bytes,
+            stored_bytes: fs::metadata(&path)
+                .map(|metadata| metadata.len())
+                .unwrap_or(file.bytes),
+            created_at: file.created_at,
+            purpose: file.purpose.clone(),
+            owner,
+            duration: audio::wav_duration(&path),
+            sample_rate: audio::wav_sample_rate(&path),
+            mime_type: sniff::sniff_file(&path).map(|media_type| media_type.mime.to_string()),
+        }
+    }
+
+    /// The file object returned by the API.
+    pub(crate) fn file_object(&self) -> FileObject {
+        FileObject {
+            id: self.id.clone(),
+            bytes: self.bytes,
+            created_at: self.created_at,
+            filename: self.filename.clone(),
+            object: "file".to_string(),
+            purpose: self.purpose.clone(),
+        }
+    }
+}
+
+// a line of the index file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum IndexEntry {
+    Put(FileRecord),
+    Delete { id: String },
+}
+
+/// Append-only JSONL index of the archived files, which keeps their metadata across restarts.
+#[derive(Debug)]
+pub(crate) struct FileIndex {
+    path: PathBuf,
+    records: HashMap<String, FileRecord>,
+}
+impl FileIndex {
+    /// Load the index in the given root of the working copy, check it against the files on disk,
+    /// and rewrite it without the stale entries.
+    ///
+    /// Records of missing files are dropped, and files without a record, e.g. archived by an
+    /// older version of the server, are recorded from what is on disk. With a remote store, the
+    /// working copy is only a cache of the store, so it is not checked: the records are brought
+    /// up to date with the store by [`sync`] instead.
+    pub(crate) fn open(root: impl AsRef<Path>, remote: bool) -> Result<Self, ServerError> {
+        let root = root.as_ref();
+        if !root.exists() {
+            fs::create_dir_all(root).map_err(|e| {
+                ServerError::Operation(format!("Failed to create the archives directory. {}", e))
+            })?;
+        }
+        let path = root.join(INDEX_FILENAME);
+
+        let mut records: HashMap<String, FileRecord> = HashMap::new();
+        if path.exists() {
+            let file = File::open(&path).map_err(|e| {
+                ServerError::Operation(format!("Failed to open the file index. {}", e))
+            })?;
+            for (idx, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.map_err(|e| {
+                    ServerError::Operation(format!("Failed to read the file index. {}", e))
+                })?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                match serde_json::from_str::<IndexEntry>(&line) {
+                    Ok(IndexEntry::Put(record)) => {
+                        records.insert(record.id.clone(), record);
+                    }
+                    Ok(IndexEntry::Delete { id }) => {
+                        records.remove(&id);
+                    }
+                    // a torn write at the end of the file is dropped
+                    Err(e) => {
+                        warn!(target: "stdout", "Skip the line {} of the file index. {}", idx + 1, e)
+                    }
+                }
+            }
+        }
+
+        if remote {
+            let index = Self { path, records };
+            index.compact()?;
+
+            return Ok(index);
+        }
+
+        // drop the records of the missing files
+        let before = records.len();
+        records.retain(|id, record| root.join(id).join(&record.filename).is_file());
+        if records.len() < before {
+            info!(target: "stdout", "Dropped {} records of missing files from the file index", before - records.len());
+        }
+
+        // record the files archived without a record
+        let mut recovered = 0;
+        for dir in fs::read_dir(root)
+            .map_err(|e| {
+                ServerError::Operation(format!("Failed to read the archives directory. {}", e))
+            })?
+            .flatten()
+        {
+            let id = dir.file_name().to_string_lossy().to_string();
+            if !dir.path().is_dir() || records.contains_key(&id) {
+                continue;
+            }
+
+            if let Some(record) = recover_record(&id, &dir.path()) {
+                records.insert(id, record);
+                recovered += 1;
+            }
+        }
+        if recovered > 0 {
+            info!(target: "stdout", "Recorded {} archived files missing from the file index", recovered);
+        }
+
+        let index = Self { path, records };
+        index.compact()?;
+
+        Ok(index)
+    }
+
+    // rewrite the index with the current records only
+    fn compact(&self) -> Result<(), ServerError> {
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        let to_err = |e: std::io::Error| {
+            ServerError::Operation(format!("Failed to write the file index. {}", e))
+        };
+
+        let mut records: Vec<&FileRecord> = self.records.values().collect();
+        records.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+
+        let mut file = File::create(&tmp_path).map_err(to_err)?;
+        for record in records {
+            let line = serde_json::to_string(&IndexEntry::Put(record.clone())).map_err(|e| {
+                ServerError::Operation(format!("Failed to serialize the file record. {}", e))
+            })?;
+            writeln!(file, "{}", line).map_err(to_err)?;
+        }
+        file.sync_all().map_err(to_err)?;
+        fs::rename(&tmp_path, &self.path).map_err(to_err)
+    }
+
+    // append an entry to the index file
+    fn append(&self, entry: &IndexEntry) -> Result<(), String> {
+        let line = serde_json::to_string(entry)
+            .map_err(|e| format!("Failed to serialize the file record. {}", e))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Failed to open the file index. {}", e))?;
+        writeln!(file, "{}", line).map_err(|e| format!("Failed to write the file index. {}", e))
+    }
+}
+
+/// Load the file index of the working copy. Called once at startup, before [`sync`].
+pub(crate) fn init() -> Result<(), ServerError> {
+    let index = FileIndex::open(store::working_root(), store::is_remote())?;
+
+    info!(target: "stdout", "file index: {} files", index.records.len());
+
+    FILE_INDEX
+        .set(Mutex::new(index))
+        .map_err(|_| ServerError::Operation("Failed to set `FILE_INDEX`.".to_string()))
+}
+
+/// Record a stored file, in the index file and in the file store. The record is kept in memory
+/// even if it can't be written.
+pub(crate) async fn put(record: FileRecord) {
+    match serde_json::to_vec(&record) {
+        Ok(data) => {
+            if let Err(e) = store::save_record(&record.id, data).await {
+                error!(target: "stdout", "Failed to save the record of {} in the file store. {}", &record.id, e);
+            }
+        }
+        Err(e) => error!(target: "stdout", "Failed to serialize the file record. {}", e),
+    }
+
+    insert(record);
+}
+
+// record a file in the index file only
+fn insert(record: FileRecord) {
+    let mut index = index().lock().unwrap();
+
+    let entry = IndexEntry::Put(record);
+    if let Err(e) = index.append(&entry) {
+        error!(target: "stdout", "{}", e);
+    }
+    if let IndexEntry::Put(record) = entry {
+        index.records.insert(record.id.clone(), record);
+    }
+}
+
+/// The record of the given file, if any.
+pub(crate) fn get(id: &str) -> Option<FileRecord> {
+    index().lock().unwrap().records.get(id).cloned()
+}
+
+/// Remove the record of the given file, if any, from the index file and from the file store.
+pub(crate) fn remove(id: &str) {
+    forget(id);
+    store::remove_record_later(id);
+}
+
+// remove the record of a file from the index file only
+fn forget(id: &str) {
+    let mut index = index().lock().unwrap();
+
+    if index.records.remove(id).is_some() {
+        if let Err(e) = index.append(&IndexEntry::Delete { id: id.to_string() }) {
+            error!(target: "stdout", "{}", e);
+        }
+    }
+}
+
+/// Bring the index up to date with the records of a remote file store, which may be shared with
+/// other instances of the server. Does nothing with a local store.
+///
+/// The records found in the store only are added. A record missing from the store is saved
+/// there again if the store still has its files, e.g. if it was written while the index was
+/// synced, and is dropped otherwise, i.e. if the file was deleted by another instance.
+pub(crate) async fn sync() -> Result<(), ServerError> {
+    if !store::is_remote() {
+        return Ok(());
+    }
+
+    let stored: HashSet<String> = store::record_ids().await?.into_iter().collect();
+    let known: HashSet<String> = index().lock().unwrap().records.keys().cloned().collect();
+
+    let mut added = 0;
+    for id in stored.difference(&known) {
+        if let Some(record) = stored_record(id).await? {
+            insert(record);
+            added += 1;
+        }
+    }
+
+    let mut dropped = 0;
+    for id in known.difference(&stored) {
+        if store::has_files(id).await? {
+            if let Some(record) = get(id) {
+                put(record).await;
+            }
+        } else {
+            forget(id);
+            dropped += 1;
+        }
+    }
+
+    if added + dropped > 0 {
+        info!(target: "stdout", "Synced the file index with the file store: {} records added, {} dropped", added, dropped);
+    }
+
+    Ok(())
+}
+
+/// All the records, oldest first.
+pub(crate) fn list() -> Vec<FileRecord> {
+    let mut records: Vec<FileRecord> = index().lock().unwrap().records.values().cloned().collect();
+    records.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+
+    records
+}
+
+/// Order of the records in a page, by creation time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// A page of the records, as returned by [`page`].
+#[derive(Debug)]
+pub(crate) struct Page {
+    pub(crate) records: Vec<FileRecord>,
+    /// More records follow the last one of the page.
+    pub(crate) has_more: bool,
+}
+
+/// The records with the given purpose, if any, in the given order, starting right after the
+/// record `after`. Returns `None` if `after` is not a known record.
+pub(crate) fn page(
+    purpose: Option<&str>,
+    order: SortOrder,
+    after: Option<&str>,
+    limit: usize,
+) -> Option<Page> {
+    let mut records = list();
+    if order == SortOrder::Desc {
+        records.reverse();
+    }
+
+    let start = match after {
+        Some(after) => records.iter().position(|record| record.id == after)? + 1,
+        None => 0,
+    };
+
+    let mut records: Vec<FileRecord> = records
+        .into_iter()
+        .skip(start)
+        .filter(|record| purpose.is_none_or(|purpose| record.purpose == purpose))
+        .take(limit + 1)
+        .collect();
+    let has_more = records.len() > limit;
+    records.truncate(limit);
+
+    Some(Page { records, has_more })
+}
+
+fn index() -> &'static Mutex<FileIndex> {
+    FILE_INDEX.get_or_init(|| {
+        Mutex::new(FileIndex {
+            path: store::working_root().join(INDEX_FILENAME),
+            records: HashMap::new(),
+        })
+    })
+}
+
+/// Record a file found in the working copy without a record, e.g. fetched from a file store
+/// shared with another instance. The record saved in the store is used if there is one.
+pub(crate) async fn recover(id: &str) -> Option<FileRecord> {
+    match stored_record(id).await {
+        Ok(Some(record)) => {
+            insert(record.clone());
+
+            return Some(record);
+        }
+        Ok(None) => (),
+        Err(e) => {
+            error!(target: "stdout", "Failed to read the record of {} from the file store. {}", id, e)
+        }
+    }
+
+    let record = recover_record(id, &store::working_dir(id))?;
+    put(record.clone()).await;
+
+    Some(record)
+}
+
+// the record of a file saved in the file store, if any
+async fn stored_record(id: &str) -> Result<Option<FileRecord>, ServerError> {
+    let data = match store::record(id).await? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+
+    match serde_json::from_slice::<FileRecord>(&data) {
+        Ok(record) if record.id == id => Ok(Some(record)),
+        Ok(_) => Ok(None),
+        Err(e) => Err(ServerError::Operation(format!(
+            "Failed to parse the record of {}. {}",
+            id, e
+        ))),
+    }
+}
+
+// build the record of an archive entry from the disk; the stored file is the oldest one which is
+// not derived from it
+fn recover_record(id: &str, dir: &Path) -> Option<FileRecord> {
+    let mut stored: Option<(SystemTime, String)> = None;
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let metadata = match entry.metadata() {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let filename = entry.file_name().to_string_lossy().to_string();
+        if is_derived(&filename) {
+            continue;
+        }
+
+        let is_older = match &stored {
+            Some((stored_modified, stored_filename)) => {
+                (modified, &filename) < (*stored_modified, stored_filename)
+            }
+            None => true,
+        };
+        if is_older {
+            stored = Some((modified, filename));
+        }
+    }
+    let (modified, filename) = stored?;
+
+    let file = FileObject {
+        id: id.to_string(),
+        bytes: fs::metadata(dir.join(&filename)).ok()?.len(),
+        created_at: modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|n| n.as_secs())
+            .unwrap_or_default(),
+        filename: filename.clone(),
+        object: "file".to_string(),
+        purpose: "assistants".to_string(),
+    };
+
+    Some(FileRecord::new(&file, filename, None))
+}
+
+// whether a file of an archive entry is made by the server next to the stored file: a stored
+// transcript, the speech of the audio, a window of a chunked or streamed request, or a file
+// still being written, e.g. an upload before its conversion
+fn is_derived(filename: &str) -> bool {
+    let stem = filename.strip_suffix(".wav").unwrap_or_default();
+    let is_window = |marker: &str| {
+        stem.rsplit_once(marker)
+            .is_some_and(|(_, n)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    };
+
+    (filename.starts_with("transcript_") && filename.ends_with(".json"))
+        || stem.ends_with(".speech")
+        || is_window(".chunk")
+        || is_window(".window")
+        || filename.ends_with(".part")
+        || filename.ends_with(".tmp")
+}
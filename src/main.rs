@@ -1,12 +1,25 @@
 #[macro_use]
 extern crate log;
 
+mod archive;
+mod audio;
 mod backend;
 mod error;
+mod form;
+mod index;
+mod shutdown;
+mod sniff;
+mod store;
+mod transcript;
+mod utils;
+mod vad;
 
 use anyhow::Result;
+use archive::Retention;
+use backend::{inference::Chunking, scheduler::QueueConfig};
 use clap::{ArgGroup, Parser, ValueEnum};
 use error::ServerError;
+use form::Limits;
 use hyper::{
     body::HttpBody,
     server::conn::AddrStream,
@@ -16,7 +29,9 @@ use hyper::{
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use store::{StoreConfig, StoreKind};
 use tokio::net::TcpListener;
+use vad::{VadConfig, VadKind};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
@@ -32,6 +47,14 @@ pub(crate) static TASK: OnceCell<TaskType> = OnceCell::new();
 pub(crate) static LLAMA_API_KEY: OnceCell<String> = OnceCell::new();
 // Use audio pre-processor
 pub(crate) static USE_AUDIO_PREPROCESSOR: OnceCell<bool> = OnceCell::new();
+// Default chunking of long audio files
+pub(crate) static AUDIO_CHUNKING: OnceCell<Option<Chunking>> = OnceCell::new();
+// Default voice activity detection
+pub(crate) static VAD: OnceCell<VadConfig> = OnceCell::new();
+// Retention policy of the archives
+pub(crate) static RETENTION: OnceCell<Retention> = OnceCell::new();
+// Limits on the request bodies and the input audio
+pub(crate) static LIMITS: OnceCell<Limits> = OnceCell::new();
 
 #[derive(Debug, Parser)]
 #[command(name = "Whisper API Server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "Whisper API Server")]
@@ -49,7 +72,7 @@ struct Cli {
     /// Number of threads to use during computation
     #[arg(long, default_value = "4")]
     threads: u64,
-    /// Number of processors to use during computation
+    /// Number of whisper contexts serving the requests. Only 1 is supported: the WebAssembly module is single-threaded and runs one inference at a time, so use `--threads` to speed up an inference
     #[arg(long, default_value = "1")]
     processors: u32,
     /// Task type.
@@ -58,6 +81,75 @@ struct Cli {
     /// Do not pre-process input audio files.
     #[arg(long, default_value = "false")]
     no_audio_preprocessor: bool,
+    /// Split audio files longer than this many seconds into windows which are transcribed separately. `0` disables chunking.
+    #[arg(long, default_value = "0")]
+    chunk_length: f64,
+    /// Overlap between consecutive windows in seconds, when chunking is enabled.
+    #[arg(long, default_value = "2")]
+    chunk_overlap: f64,
+    /// Voice activity detector used to skip the silences of input audio files.
+    #[arg(long, value_enum, default_value = "none")]
+    vad: VadKind,
+    /// Minimum length in seconds of the silences skipped by the voice activity detector.
+    #[arg(long, default_value = "1")]
+    vad_min_silence: f64,
+    /// Delete archived files older than this many seconds.
+    #[arg(long)]
+    archive_max_age: Option<u64>,
+    /// Delete the oldest archived files while the archives take more than this many bytes.
+    #[arg(long)]
+    archive_max_bytes: Option<u64>,
+    /// Delete the oldest archived files while there are more than this many.
+    #[arg(long)]
+    archive_max_files: Option<u64>,
+    /// Interval in seconds between two collections of the archives.
+    #[arg(long, default_value = "60")]
+    archive_gc_interval: u64,
+    /// Delete the audio of a transcription or translation request as soon as the response is sent.
+    #[arg(long, default_value = "false")]
+    ephemeral_uploads: bool,
+    /// Seconds a finished transcription job can still be polled before it is forgotten.
+    #[arg(long, default_value = "3600")]
+    job_ttl: u64,
+    /// Refuse request bodies larger than this many bytes with a 413.
+    #[arg(long)]
+    max_request_bytes: Option<u64>,
+    /// Refuse files, and other fields of multipart bodies, larger than this many bytes with a 413.
+    #[arg(long)]
+    max_file_bytes: Option<u64>,
+    /// Refuse input audio longer than this many seconds, once decoded, with a 400.
+    #[arg(long)]
+    max_audio_duration: Option<f64>,
+    /// Maximum number of requests waiting for the whisper context. More requests are refused with a 429.
+    #[arg(long, default_value = "32")]
+    queue_depth: usize,
+    /// Refuse a request with a 503 once it has waited this many seconds for the whisper context.
+    #[arg(long)]
+    queue_timeout: Option<u64>,
+    /// Maximum number of live audio sessions, on `/v1/audio/stream` and `/v1/realtime`. More sessions are refused with a 429.
+    #[arg(long, default_value = "8")]
+    max_sessions: usize,
+    /// Backend of the store of the uploaded and converted files.
+    #[arg(long, value_enum, default_value = "local")]
+    file_store: StoreKind,
+    /// Directory of the local file store, or key prefix of the S3 file store.
+    #[arg(long, default_value = "archives")]
+    file_store_root: String,
+    /// Endpoint of the S3 file store, e.g. `http://localhost:9000`. The credentials are read from the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment variables.
+    #[arg(long)]
+    s3_endpoint: Option<String>,
+    /// Bucket of the S3 file store.
+    #[arg(long)]
+    s3_bucket: Option<String>,
+    /// Region of the S3 file store.
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+    /// Transcribe a bundled sample at startup. `/ready` reports the server as ready only once the transcript is right.
+    #[arg(long, default_value = "false")]
+    warmup: bool,
+    /// Seconds given to the in-flight requests and the queued jobs to finish once a shutdown is requested with `POST /shutdown`.
+    #[arg(long, default_value = "30")]
+    grace_period: u64,
     /// Port number
     #[arg(long, default_value = DEFAULT_PORT, value_parser = clap::value_parser!(u16), group = "socket_address_group")]
     port: u16,
@@ -111,6 +203,16 @@ async fn main() -> Result<(), ServerError> {
     info!(target: "stdout", "threads: {}", cli.threads);
 
     // log the number of processors
+    if cli.processors != 1 {
+        let err_msg = format!(
+            "Invalid number of processors: {}. Only 1 is supported: the server runs one inference at a time. Use `--threads` to speed up an inference, or run several instances to serve requests in parallel.",
+            cli.processors
+        );
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg));
+    }
     info!(target: "stdout", "processors: {}", cli.processors);
 
     // log the task type
@@ -127,12 +229,131 @@ async fn main() -> Result<(), ServerError> {
             ServerError::Operation("Failed to set `USE_AUDIO_PREPROCESSOR`.".to_string())
         })?;
 
+    let chunking = Chunking::new(cli.chunk_length, cli.chunk_overlap).map_err(|e| {
+        let err_msg = format!("Failed to set the audio chunking. {}", e);
+
+        error!(target: "stdout", "{}", err_msg);
+
+        ServerError::Operation(err_msg)
+    })?;
+    match chunking {
+        Some(chunking) => {
+            info!(target: "stdout", "chunk length: {}s, chunk overlap: {}s", chunking.length, chunking.overlap)
+        }
+        None => info!(target: "stdout", "chunk length: disabled"),
+    }
+
+    AUDIO_CHUNKING
+        .set(chunking)
+        .map_err(|_| ServerError::Operation("Failed to set `AUDIO_CHUNKING`.".to_string()))?;
+
+    if !(cli.vad_min_silence >= 0.0 && cli.vad_min_silence.is_finite()) {
+        let err_msg = format!(
+            "Invalid VAD minimum silence: {}. It should be a non-negative number of seconds.",
+            cli.vad_min_silence
+        );
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg));
+    }
+    info!(target: "stdout", "vad: {}, vad min silence: {}s", cli.vad, cli.vad_min_silence);
+
+    VAD.set(VadConfig {
+        kind: cli.vad,
+        min_silence: cli.vad_min_silence,
+    })
+    .map_err(|_| ServerError::Operation("Failed to set `VAD`.".to_string()))?;
+
+    let retention = Retention {
+        max_age: cli.archive_max_age,
+        max_bytes: cli.archive_max_bytes,
+        max_files: cli.archive_max_files,
+        gc_interval: cli.archive_gc_interval,
+        ephemeral_uploads: cli.ephemeral_uploads,
+        job_ttl: cli.job_ttl,
+    };
+    info!(target: "stdout", "archive max age: {:?}, max bytes: {:?}, max files: {:?}, gc interval: {}s, ephemeral uploads: {}, job ttl: {}s", retention.max_age, retention.max_bytes, retention.max_files, retention.gc_interval, retention.ephemeral_uploads, retention.job_ttl);
+
+    RETENTION
+        .set(retention)
+        .map_err(|_| ServerError::Operation("Failed to set `RETENTION`.".to_string()))?;
+
+    if let Some(max_audio_duration) = cli.max_audio_duration {
+        if !(max_audio_duration > 0.0 && max_audio_duration.is_finite()) {
+            let err_msg = format!(
+                "Invalid maximum audio duration: {}. It should be a positive number of seconds.",
+                max_audio_duration
+            );
+
+            error!(target: "stdout", "{}", err_msg);
+
+            return Err(ServerError::Operation(err_msg));
+        }
+    }
+    let limits = Limits {
+        max_request_bytes: cli.max_request_bytes,
+        max_file_bytes: cli.max_file_bytes,
+        max_audio_duration: cli.max_audio_duration,
+    };
+    info!(target: "stdout", "max request bytes: {:?}, max file bytes: {:?}, max audio duration: {:?}", limits.max_request_bytes, limits.max_file_bytes, limits.max_audio_duration);
+
+    LIMITS
+        .set(limits)
+        .map_err(|_| ServerError::Operation("Failed to set `LIMITS`.".to_string()))?;
+
+    if cli.queue_depth == 0 {
+        let err_msg = "Invalid queue depth: 0. At least one request should be able to wait for the whisper context.";
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
+    let queue = QueueConfig {
+        depth: cli.queue_depth,
+        timeout: cli.queue_timeout,
+        sessions: cli.max_sessions,
+    };
+    info!(target: "stdout", "queue depth: {}, queue timeout: {:?}, max sessions: {}", queue.depth, queue.timeout, queue.sessions);
+
+    backend::scheduler::init(queue)?;
+
+    info!(target: "stdout", "grace period: {}s", cli.grace_period);
+
+    shutdown::init(cli.grace_period)?;
+
+    info!(target: "stdout", "warmup: {}", cli.warmup);
+
+    backend::health::init(cli.warmup)?;
+
+    let file_store = StoreConfig {
+        kind: cli.file_store,
+        root: cli.file_store_root,
+        s3_endpoint: cli.s3_endpoint,
+        s3_bucket: cli.s3_bucket,
+        s3_region: cli.s3_region,
+    };
+    info!(target: "stdout", "file store: {}, root: {}", file_store.kind, file_store.root);
+    if file_store.kind == StoreKind::S3 {
+        info!(target: "stdout", "s3 endpoint: {:?}, bucket: {:?}, region: {}", file_store.s3_endpoint, file_store.s3_bucket, file_store.s3_region);
+    }
+
+    store::init(&file_store)?;
+
+    // load the metadata of the archived files, and of the files of a shared store
+    index::init()?;
+    index::sync().await?;
+
+    // record the stored transcripts
+    backend::transcripts::init()?;
+
     // create a Metadata instance
     let metadata = llama_core::metadata::whisper::WhisperMetadataBuilder::new(
         &cli.model_name,
         &cli.model_alias,
     )
     .with_model_path(&cli.model)
+    .with_threads(cli.threads)
     .enable_plugin_log(true)
     .enable_debug_log(true)
     .build();
@@ -180,6 +401,9 @@ async fn main() -> Result<(), ServerError> {
         port: addr.port().to_string(),
         translate_model,
         transcribe_model,
+        retention,
+        limits,
+        file_store,
         extras: HashMap::new(),
     };
     SERVER_INFO
@@ -194,20 +418,41 @@ async fn main() -> Result<(), ServerError> {
             conn.local_addr().to_string()
         );
 
-        async move { Ok::<_, Error>(service_fn(handle_request)) }
+        // the handlers read the address of the peer from the request extensions
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, Error>(service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(remote_addr);
+                handle_request(req)
+            }))
+        }
     });
 
+    // collect the archives and the finished jobs in the background
+    tokio::spawn(archive::run_gc(retention));
+
+    // the server answers the probes while the warmup runs
+    if cli.warmup {
+        tokio::spawn(backend::health::warmup());
+    }
+
     let tcp_listener = TcpListener::bind(addr).await.unwrap();
     info!(target: "stdout", "Listening on {}", addr);
 
+    // stop accepting connections once the shutdown is requested, and wait for the open ones to
+    // be closed, until the end of the grace period
     let server = Server::from_tcp(tcp_listener.into_std().unwrap())
         .unwrap()
-        .serve(new_service);
+        .serve(new_service)
+        .with_graceful_shutdown(shutdown::requested());
 
-    match server.await {
-        Ok(_) => Ok(()),
-        Err(e) => Err(ServerError::Operation(e.to_string())),
+    tokio::select! {
+        served = server => served.map_err(|e| ServerError::Operation(e.to_string()))?,
+        _ = shutdown::grace_period_over() => (),
     }
+
+    // the jobs run outside of the connections
+    shutdown::drain().await
 }
 
 async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
@@ -260,8 +505,19 @@ async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Err
         }
     }
 
+    // while draining, only the requests which start no new work are served
+    if shutdown::is_draining() && req.method() == hyper::http::Method::POST {
+        return Ok(error::service_unavailable(
+            "The server is shutting down. Retry on another instance.",
+            1,
+        ));
+    }
+
     let response = match root_path.as_str() {
         "/echo" => Response::new(Body::from("echo test")),
+        "/health" => backend::health::health_handler().await,
+        "/ready" => backend::health::ready_handler().await,
+        "/shutdown" => shutdown::shutdown_handler(req).await,
         "/v1" => backend::handle_llama_request(req).await,
         _ => error::invalid_endpoint("The requested service endpoint is not found."),
     };
@@ -396,6 +652,9 @@ pub(crate) struct ApiServer {
     translate_model: Option<ModelConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     transcribe_model: Option<ModelConfig>,
+    retention: Retention,
+    limits: Limits,
+    file_store: StoreConfig,
     extras: HashMap<String, String>,
 }
 
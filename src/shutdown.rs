@@ -0,0 +1,177 @@
+use crate::{
+    backend::{jobs, scheduler},
+    error::{self, ServerError},
+};
+use hyper::{Body, Method, Request, Response};
+use once_cell::sync::OnceCell;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+// draining of the server before it exits
+static SHUTDOWN: OnceCell<Shutdown> = OnceCell::new();
+
+// interval between two checks of the work left while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+struct Shutdown {
+    // seconds left to the in-flight and queued work once the shutdown is requested
+    grace_period: u64,
+    // when the shutdown was requested
+    requested_at: OnceCell<Instant>,
+    sender: watch::Sender<bool>,
+}
+
+/// Set the grace period of the shutdown, in seconds. Called once at startup.
+pub(crate) fn init(grace_period: u64) -> Result<(), ServerError> {
+    let (sender, _) = watch::channel(false);
+
+    SHUTDOWN
+        .set(Shutdown {
+            grace_period,
+            requested_at: OnceCell::new(),
+            sender,
+        })
+        .map_err(|_| ServerError::Operation("Failed to set `SHUTDOWN`.".to_string()))
+}
+
+/// Start draining the server: the listener stops accepting connections, `/ready` reports the
+/// server as not ready, and new requests are refused, while the work in flight finishes. Calling
+/// it again has no effect.
+pub(crate) fn begin() {
+    let shutdown = shutdown();
+
+    if shutdown.requested_at.set(Instant::now()).is_ok() {
+        let (queued, running, sessions, jobs) = pending();
+        info!(target: "stdout", "Shutting down: drain {} queued and {} running requests, {} live audio sessions, and {} jobs, within {}s", queued, running, sessions, jobs, shutdown.grace_period);
+
+        shutdown.sender.send_replace(true);
+    }
+}
+
+/// Whether the server is shutting down.
+pub(crate) fn is_draining() -> bool {
+    shutdown().requested_at.get().is_some()
+}
+
+/// Resolve once the shutdown is requested.
+pub(crate) async fn requested() {
+    let mut receiver = shutdown().sender.subscribe();
+
+    // the sender lives in a static, so the channel is never closed
+    let _ = receiver.wait_for(|requested| *requested).await;
+}
+
+/// Resolve once the grace period of a requested shutdown is over.
+pub(crate) async fn grace_period_over() {
+    requested().await;
+
+    tokio::time::sleep_until(deadline().into()).await;
+}
+
+/// Wait, until the end of the grace period, for the queued and running requests, the live audio
+/// sessions and the jobs to finish. Returns an error telling what was dropped if some work is left.
+pub(crate) async fn drain() -> Result<(), ServerError> {
+    let deadline = deadline();
+
+    loop {
+        let (queued, running, sessions, jobs) = pending();
+        if queued + running + sessions + jobs == 0 {
+            info!(target: "stdout", "Shutdown complete: all the requests, sessions and jobs finished");
+
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            let err_msg = format!(
+                "The grace period of {}s is over: {} queued and {} running requests, {} live audio sessions, and {} jobs, are dropped.",
+                shutdown().grace_period,
+                queued,
+                running,
+                sessions,
+                jobs
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::Operation(err_msg));
+        }
+
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Handle `POST /shutdown`, sent e.g. by the `preStop` hook of a Kubernetes pod. Only accepted
+/// from the local host.
+pub(crate) async fn shutdown_handler(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::POST {
+        let err_msg = "Invalid HTTP Method.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    let local = req
+        .extensions()
+        .get::<SocketAddr>()
+        .is_some_and(|remote_addr| remote_addr.ip().is_loopback());
+    if !local {
+        let err_msg = "The shutdown can only be requested from the local host.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::unauthorized(err_msg);
+    }
+
+    begin();
+
+    let (queued, running, sessions, jobs) = pending();
+    let body = serde_json::json!({
+        "status": "draining",
+        "grace_period": shutdown().grace_period,
+        "queued": queued,
+        "running": running,
+        "sessions": sessions,
+        "jobs": jobs,
+    });
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .status(hyper::StatusCode::ACCEPTED)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+// queued and running requests, open live audio sessions, and jobs not finished
+fn pending() -> (usize, usize, usize, usize) {
+    let queue = scheduler::status();
+
+    (queue.queued, queue.running, queue.sessions, jobs::pending())
+}
+
+// end of the grace period, or now if the shutdown is not requested
+fn deadline() -> Instant {
+    let shutdown = shutdown();
+
+    match shutdown.requested_at.get() {
+        Some(requested_at) => *requested_at + Duration::from_secs(shutdown.grace_period),
+        None => Instant::now(),
+    }
+}
+
+fn shutdown() -> &'static Shutdown {
+    SHUTDOWN.get_or_init(|| Shutdown {
+        grace_period: 30,
+        requested_at: OnceCell::new(),
+        sender: watch::channel(false).0,
+    })
+}
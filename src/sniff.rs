@@ -0,0 +1,99 @@
+use std::{fs::File, io::Read, path::Path};
+
+// number of bytes read to classify a file
+const SNIFF_LEN: usize = 512;
+
+/// Media type of a file, detected from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MediaType {
+    pub(crate) mime: &'static str,
+    /// Audio the converter can decode.
+    pub(crate) audio: bool,
+}
+impl MediaType {
+    const fn audio(mime: &'static str) -> Self {
+        Self { mime, audio: true }
+    }
+
+    const fn other(mime: &'static str) -> Self {
+        Self { mime, audio: false }
+    }
+
+    /// Whether the file is a wav file, which whisper reads without conversion.
+    pub(crate) fn is_wav(&self) -> bool {
+        self.mime == "audio/wav"
+    }
+}
+
+/// Classify a file from its first bytes, or `None` for unsupported content.
+///
+/// Audio containers are recognized by their magic bytes. Other files are accepted as PNG images
+/// or UTF-8 text, which is reported as markdown if the filename ends with `.md`.
+pub(crate) fn sniff(data: &[u8], filename: &str) -> Option<MediaType> {
+    let data = &data[..data.len().min(SNIFF_LEN)];
+
+    let media_type = match data {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => {
+            MediaType::audio("audio/wav")
+        }
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'F' | b'C', ..] => {
+            MediaType::audio("audio/aiff")
+        }
+        [b'f', b'L', b'a', b'C', ..] => MediaType::audio("audio/flac"),
+        [b'O', b'g', b'g', b'S', ..] => MediaType::audio("audio/ogg"),
+        [b'I', b'D', b'3', ..] => MediaType::audio("audio/mpeg"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'3', b'g', ..] => MediaType::audio("audio/3gpp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => MediaType::audio("audio/mp4"),
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => MediaType::audio("audio/webm"),
+        // frame sync of an ADTS stream, whose layer bits are zero
+        [0xff, b1, ..] if b1 & 0xf6 == 0xf0 => MediaType::audio("audio/aac"),
+        // frame sync of an MPEG audio stream, with a valid version and layer
+        [0xff, b1, ..] if b1 & 0xe0 == 0xe0 && b1 & 0x18 != 0x08 && b1 & 0x06 != 0 => {
+            MediaType::audio("audio/mpeg")
+        }
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => MediaType::other("image/png"),
+        _ if is_text(data) => match filename.to_lowercase().ends_with(".md") {
+            true => MediaType::other("text/markdown; charset=utf-8"),
+            false => MediaType::other("text/plain; charset=utf-8"),
+        },
+        _ => return None,
+    };
+
+    Some(media_type)
+}
+
+/// Classify a stored file from its first bytes.
+pub(crate) fn sniff_file(path: impl AsRef<Path>) -> Option<MediaType> {
+    let path = path.as_ref();
+
+    let mut data = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)
+        .ok()?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut data)
+        .ok()?;
+
+    let filename = path
+        .file_name()
+        .map(|filename| filename.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    sniff(&data, &filename)
+}
+
+// non-empty UTF-8 without control characters other than whitespace; the data may end in the
+// middle of a character
+fn is_text(data: &[u8]) -> bool {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&data[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| !c.is_control() || c.is_ascii_whitespace())
+}
@@ -0,0 +1,1189 @@
+use crate::error::ServerError;
+use clap::ValueEnum;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use hyper::{
+    body::to_bytes, client::HttpConnector, Body, Client, Method, Request, Response, StatusCode,
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// Directory of the working copy of the stored files.
+///
+/// llama-core reads the audio from `archives/{file_id}/{filename}` by itself, so the working
+/// copy can't be moved; `--file-store-root` moves the store only.
+pub(crate) const WORKING_COPY: &str = "archives";
+
+// prefix of the keys of the file records; file ids have no dots, so it can't clash with a file
+const RECORD_PREFIX: &str = ".index/";
+
+// size of the parts of a multipart upload to the S3 store, which is also the size above which
+// a file is uploaded in parts
+const S3_PART_BYTES: usize = 8 * 1024 * 1024;
+
+// store of the uploaded and converted audio files
+static FILE_STORE: OnceCell<Box<dyn FileStore>> = OnceCell::new();
+
+/// Backend of the file store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StoreKind {
+    /// Files in a directory of the local filesystem.
+    #[value(name = "local")]
+    Local,
+    /// Files in memory, lost on restart.
+    #[value(name = "memory")]
+    Memory,
+    /// Objects in a bucket of an S3-compatible object storage.
+    #[value(name = "s3")]
+    S3,
+}
+impl std::fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreKind::Local => write!(f, "local"),
+            StoreKind::Memory => write!(f, "memory"),
+            StoreKind::S3 => write!(f, "s3"),
+        }
+    }
+}
+
+/// File store settings set from the command line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct StoreConfig {
+    pub(crate) kind: StoreKind,
+    /// Directory of the local store, or key prefix of the S3 store.
+    pub(crate) root: String,
+    /// Endpoint of the S3 store, e.g. `http://localhost:9000`.
+    pub(crate) s3_endpoint: Option<String>,
+    pub(crate) s3_bucket: Option<String>,
+    pub(crate) s3_region: String,
+}
+
+/// Storage of the uploaded and converted files, addressed by `{file_id}/{filename}` keys.
+///
+/// The inference always reads the audio from the [`WORKING_COPY`] directory, which holds a
+/// working copy of the stored files. Stores other than a local store rooted there keep the files
+/// durable, and shared between the instances of the server.
+pub(crate) trait FileStore: Send + Sync {
+    /// Store the given data under the given key, replacing the previous data if any.
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), ServerError>>;
+
+    /// The data stored under the given key, or `None` if there is no such key.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, ServerError>>;
+
+    /// Delete the data stored under the given key. Deleting a missing key is not an error.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), ServerError>>;
+
+    /// The keys starting with the given prefix, in lexicographic order.
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>, ServerError>>;
+
+    /// Store the file at the given path under the given key. By default the file is read in
+    /// memory; stores which can, copy it piece by piece.
+    fn put_file<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let data = fs::read(path)
+                .map_err(|e| ServerError::Operation(format!("Failed to read {}. {}", key, e)))?;
+
+            self.put(key, data).await
+        })
+    }
+
+    /// Write the data stored under the given key to the given path. Returns `false` if there is
+    /// no such key.
+    fn get_file<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<bool, ServerError>> {
+        Box::pin(async move {
+            let data = match self.get(key).await? {
+                Some(data) => data,
+                None => return Ok(false),
+            };
+
+            fs::write(path, data)
+                .map(|_| true)
+                .map_err(|e| ServerError::Operation(format!("Failed to write {}. {}", key, e)))
+        })
+    }
+
+    /// Whether the store is the working copy itself, in which case nothing is copied.
+    fn is_working_copy(&self) -> bool {
+        false
+    }
+}
+
+/// Store in a directory of the local filesystem.
+#[derive(Debug)]
+pub(crate) struct LocalStore {
+    root: PathBuf,
+}
+impl LocalStore {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+impl FileStore for LocalStore {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let path = self.root.join(key);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(|e| {
+                    ServerError::Operation(format!(
+                        "Failed to create the directory of {}. {}",
+                        key, e
+                    ))
+                })?;
+            }
+
+            fs::write(&path, data)
+                .map_err(|e| ServerError::Operation(format!("Failed to write {}. {}", key, e)))
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, ServerError>> {
+        Box::pin(async move {
+            match fs::read(self.root.join(key)) {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(ServerError::Operation(format!(
+                    "Failed to read {}. {}",
+                    key, e
+                ))),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let path = self.root.join(key);
+            match fs::remove_file(&path) {
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    return Err(ServerError::Operation(format!(
+                        "Failed to delete {}. {}",
+                        key, e
+                    )))
+                }
+            }
+
+            // drop the directory of the file once it is empty
+            if let Some(dir) = path.parent().filter(|dir| *dir != self.root) {
+                let _ = fs::remove_dir(dir);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>, ServerError>> {
+        Box::pin(async move {
+            // keys are `{dir}/{filename}`, so only the directories matching the prefix are read
+            let (dir_prefix, _) = prefix.split_once('/').unwrap_or((prefix, ""));
+
+            let mut keys = Vec::new();
+            let dirs = match fs::read_dir(&self.root) {
+                Ok(dirs) => dirs,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(keys),
+                Err(e) => {
+                    return Err(ServerError::Operation(format!(
+                        "Failed to read the file store. {}",
+                        e
+                    )))
+                }
+            };
+            for dir in dirs.flatten() {
+                let dir_name = dir.file_name().to_string_lossy().to_string();
+                if !dir.path().is_dir() || !dir_name.starts_with(dir_prefix) {
+                    continue;
+                }
+
+                for file in fs::read_dir(dir.path()).into_iter().flatten().flatten() {
+                    let key = format!("{}/{}", dir_name, file.file_name().to_string_lossy());
+                    if file.path().is_file() && key.starts_with(prefix) {
+                        keys.push(key);
+                    }
+                }
+            }
+            keys.sort();
+
+            Ok(keys)
+        })
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let target = self.root.join(key);
+            if let Some(dir) = target.parent() {
+                fs::create_dir_all(dir).map_err(|e| {
+                    ServerError::Operation(format!(
+                        "Failed to create the directory of {}. {}",
+                        key, e
+                    ))
+                })?;
+            }
+
+            fs::copy(path, &target)
+                .map(|_| ())
+                .map_err(|e| ServerError::Operation(format!("Failed to write {}. {}", key, e)))
+        })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<bool, ServerError>> {
+        Box::pin(async move {
+            match fs::copy(self.root.join(key), path) {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(ServerError::Operation(format!(
+                    "Failed to read {}. {}",
+                    key, e
+                ))),
+            }
+        })
+    }
+
+    fn is_working_copy(&self) -> bool {
+        same_dir(&self.root, Path::new(WORKING_COPY))
+    }
+}
+
+/// Store in memory. The files are lost when the server stops.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStore {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+}
+impl FileStore for MemoryStore {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), ServerError>> {
+        self.files.lock().unwrap().insert(key.to_string(), data);
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, ServerError>> {
+        let data = self.files.lock().unwrap().get(key).cloned();
+
+        Box::pin(async move { Ok(data) })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), ServerError>> {
+        self.files.lock().unwrap().remove(key);
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>, ServerError>> {
+        let mut keys: Vec<String> = self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+
+        Box::pin(async move { Ok(keys) })
+    }
+}
+
+/// Store in a bucket of an S3-compatible object storage, such as MinIO.
+///
+/// Requests use path-style urls and are signed with AWS Signature Version 4. Only `http`
+/// endpoints are supported.
+#[derive(Debug)]
+pub(crate) struct S3Store {
+    client: Client<HttpConnector>,
+    /// Endpoint without the trailing slash.
+    endpoint: String,
+    bucket: String,
+    region: String,
+    /// Prefix of the object keys, empty or ending with a slash.
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+}
+impl S3Store {
+    /// Create a store with the credentials in the `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY` environment variables.
+    pub(crate) fn new(
+        endpoint: impl Into<String>,
+        bucket: impl Into<String>,
+        region: impl Into<String>,
+        prefix: &str,
+    ) -> Result<Self, ServerError> {
+        let endpoint = endpoint.into().trim_end_matches('/').to_string();
+        if !endpoint.starts_with("http://") {
+            return Err(ServerError::Operation(format!(
+                "Invalid S3 endpoint: {}. Only `http://` endpoints are supported.",
+                endpoint
+            )));
+        }
+
+        let env = |name: &str| {
+            std::env::var(name).map_err(|_| {
+                ServerError::Operation(format!(
+                    "The `{}` environment variable is required by the S3 file store.",
+                    name
+                ))
+            })
+        };
+
+        let prefix = prefix.trim_matches('/');
+
+        Ok(Self {
+            client: Client::new(),
+            endpoint,
+            bucket: bucket.into(),
+            region: region.into(),
+            prefix: match prefix.is_empty() {
+                true => String::new(),
+                false => format!("{}/", prefix),
+            },
+            access_key: env("AWS_ACCESS_KEY_ID")?,
+            secret_key: env("AWS_SECRET_ACCESS_KEY")?,
+        })
+    }
+
+    // send a signed request, and read the whole response
+    async fn send(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<(StatusCode, Vec<u8>), ServerError> {
+        let response = self.request(method, key, query, body).await?;
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.map_err(|e| {
+            ServerError::Operation(format!("Failed to read the S3 response. {}", e))
+        })?;
+
+        Ok((status, body.to_vec()))
+    }
+
+    // send a signed request; the path is relative to the bucket, and the query is given as
+    // unencoded pairs
+    async fn request(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Response<Body>, ServerError> {
+        let path = match key {
+            Some(key) => format!(
+                "/{}/{}",
+                uri_encode(&self.bucket, false),
+                uri_encode(&format!("{}{}", self.prefix, key), false)
+            ),
+            None => format!("/{}", uri_encode(&self.bucket, false)),
+        };
+        let mut pairs: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        pairs.sort();
+        let query = pairs
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let host = self
+            .endpoint
+            .trim_start_matches("http://")
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let (date, timestamp) = amz_date(SystemTime::now());
+        let payload_hash = hex(&hmac_sha256::Hash::hash(&body));
+
+        // canonical request of the signature version 4
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, host, payload_hash, timestamp, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&hmac_sha256::Hash::hash(canonical_request.as_bytes()))
+        );
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac_sha256::HMAC::mac(date.as_bytes(), format!("AWS4{}", self.secret_key)),
+            |key, part| hmac_sha256::HMAC::mac(part.as_bytes(), key),
+        );
+        let signature = hex(&hmac_sha256::HMAC::mac(
+            string_to_sign.as_bytes(),
+            signing_key,
+        ));
+
+        let uri = match query.is_empty() {
+            true => format!("{}{}", self.endpoint, path),
+            false => format!("{}{}?{}", self.endpoint, path, query),
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("host", host)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", timestamp)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key, scope, signed_headers, signature
+                ),
+            )
+            .body(Body::from(body))
+            .map_err(|e| {
+                ServerError::Operation(format!("Failed to build the S3 request. {}", e))
+            })?;
+
+        self.client
+            .request(request)
+            .await
+            .map_err(|e| ServerError::Operation(format!("Failed to send the S3 request. {}", e)))
+    }
+
+    // upload a file in parts of `S3_PART_BYTES`, so that only one part is held in memory
+    async fn put_parts(&self, key: &str, file: &mut File) -> Result<(), ServerError> {
+        let upload_id = match self
+            .send(Method::POST, Some(key), &[("uploads", "")], Vec::new())
+            .await?
+        {
+            (status, body) if status.is_success() => {
+                xml_values(&String::from_utf8_lossy(&body), "UploadId")
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| {
+                        ServerError::Operation(format!(
+                            "Failed to start the upload of {} to the S3 file store. No upload id.",
+                            key
+                        ))
+                    })?
+            }
+            (status, body) => return Err(s3_error("start the upload of", key, status, &body)),
+        };
+
+        let result = self.upload_parts(key, &upload_id, file).await;
+        if result.is_err() {
+            // the parts of an aborted upload are freed by the store
+            let _ = self
+                .send(
+                    Method::DELETE,
+                    Some(key),
+                    &[("uploadId", &upload_id)],
+                    Vec::new(),
+                )
+                .await;
+        }
+
+        result
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        file: &mut File,
+    ) -> Result<(), ServerError> {
+        let mut etags = Vec::new();
+        loop {
+            let mut part = Vec::with_capacity(S3_PART_BYTES);
+            Read::by_ref(file)
+                .take(S3_PART_BYTES as u64)
+                .read_to_end(&mut part)
+                .map_err(|e| ServerError::Operation(format!("Failed to read {}. {}", key, e)))?;
+            // an empty file is uploaded as a single empty part
+            if part.is_empty() && !etags.is_empty() {
+                break;
+            }
+            let last = part.len() < S3_PART_BYTES;
+
+            let part_number = (etags.len() + 1).to_string();
+            let response = self
+                .request(
+                    Method::PUT,
+                    Some(key),
+                    &[("partNumber", &part_number), ("uploadId", upload_id)],
+                    part,
+                )
+                .await?;
+            let status = response.status();
+            let etag = response
+                .headers()
+                .get("etag")
+                .and_then(|etag| etag.to_str().ok())
+                .map(|etag| etag.to_string());
+            let body = to_bytes(response.into_body()).await.map_err(|e| {
+                ServerError::Operation(format!("Failed to read the S3 response. {}", e))
+            })?;
+            match etag {
+                Some(etag) if status.is_success() => etags.push(etag),
+                _ => return Err(s3_error("upload a part of", key, status, &body)),
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        for (idx, etag) in etags.iter().enumerate() {
+            complete.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                idx + 1,
+                etag
+            ));
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+
+        // the completion may fail after a 200, with an error in the body
+        match self
+            .send(
+                Method::POST,
+                Some(key),
+                &[("uploadId", upload_id)],
+                complete.into_bytes(),
+            )
+            .await?
+        {
+            (status, body)
+                if status.is_success() && !String::from_utf8_lossy(&body).contains("<Error>") =>
+            {
+                Ok(())
+            }
+            (status, body) => Err(s3_error("complete the upload of", key, status, &body)),
+        }
+    }
+}
+impl FileStore for S3Store {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            match self.send(Method::PUT, Some(key), &[], data).await? {
+                (status, _) if status.is_success() => Ok(()),
+                (status, body) => Err(s3_error("put", key, status, &body)),
+            }
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, ServerError>> {
+        Box::pin(async move {
+            match self.send(Method::GET, Some(key), &[], Vec::new()).await? {
+                (status, body) if status.is_success() => Ok(Some(body)),
+                (StatusCode::NOT_FOUND, _) => Ok(None),
+                (status, body) => Err(s3_error("get", key, status, &body)),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            match self
+                .send(Method::DELETE, Some(key), &[], Vec::new())
+                .await?
+            {
+                (status, _) if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+                (status, body) => Err(s3_error("delete", key, status, &body)),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<String>, ServerError>> {
+        Box::pin(async move {
+            let full_prefix = format!("{}{}", self.prefix, prefix);
+
+            let mut keys = Vec::new();
+            let mut token: Option<String> = None;
+            loop {
+                let mut query = vec![("list-type", "2"), ("prefix", full_prefix.as_str())];
+                if let Some(token) = token.as_deref() {
+                    query.push(("continuation-token", token));
+                }
+
+                let body = match self.send(Method::GET, None, &query, Vec::new()).await? {
+                    (status, body) if status.is_success() => {
+                        String::from_utf8_lossy(&body).to_string()
+                    }
+                    (status, body) => return Err(s3_error("list", prefix, status, &body)),
+                };
+
+                keys.extend(
+                    xml_values(&body, "Key").into_iter().filter_map(|key| {
+                        key.strip_prefix(&self.prefix).map(|key| key.to_string())
+                    }),
+                );
+
+                let truncated =
+                    xml_values(&body, "IsTruncated").first().map(|s| s.as_str()) == Some("true");
+                token = xml_values(&body, "NextContinuationToken")
+                    .into_iter()
+                    .next();
+                if !truncated || token.is_none() {
+                    break;
+                }
+            }
+            keys.sort();
+
+            Ok(keys)
+        })
+    }
+
+    fn put_file<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), ServerError>> {
+        Box::pin(async move {
+            let to_err = |e: std::io::Error| {
+                ServerError::Operation(format!("Failed to read {}. {}", key, e))
+            };
+
+            let mut file = File::open(path).map_err(to_err)?;
+            let len = file.metadata().map_err(to_err)?.len();
+            if len > S3_PART_BYTES as u64 {
+                return self.put_parts(key, &mut file).await;
+            }
+
+            let mut data = Vec::with_capacity(len as usize);
+            file.read_to_end(&mut data).map_err(to_err)?;
+
+            self.put(key, data).await
+        })
+    }
+
+    fn get_file<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<bool, ServerError>> {
+        Box::pin(async move {
+            let response = self
+                .request(Method::GET, Some(key), &[], Vec::new())
+                .await?;
+            let status = response.status();
+            if status == StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+            if !status.is_success() {
+                let body = to_bytes(response.into_body()).await.unwrap_or_default();
+                return Err(s3_error("get", key, status, &body));
+            }
+
+            // the object is written as it arrives, under a temporary name until it is complete
+            let tmp_path = path.with_extension("part");
+            let to_err = |e: std::io::Error| {
+                ServerError::Operation(format!("Failed to write {}. {}", key, e))
+            };
+            let mut file = File::create(&tmp_path).map_err(to_err)?;
+            let mut body = response.into_body();
+            while let Some(chunk) = body.next().await {
+                let written = chunk
+                    .map_err(|e| {
+                        ServerError::Operation(format!("Failed to read the S3 response. {}", e))
+                    })
+                    .and_then(|chunk| file.write_all(&chunk).map_err(to_err));
+                if let Err(e) = written {
+                    let _ = fs::remove_file(&tmp_path);
+
+                    return Err(e);
+                }
+            }
+            drop(file);
+
+            fs::rename(&tmp_path, path).map(|_| true).map_err(to_err)
+        })
+    }
+}
+
+/// Create the file store from the command line settings. Called once at startup.
+pub(crate) fn init(config: &StoreConfig) -> Result<(), ServerError> {
+    let file_store: Box<dyn FileStore> = match config.kind {
+        StoreKind::Local => Box::new(LocalStore::new(&config.root)),
+        StoreKind::Memory => Box::new(MemoryStore::default()),
+        StoreKind::S3 => {
+            let (endpoint, bucket) = match (&config.s3_endpoint, &config.s3_bucket) {
+                (Some(endpoint), Some(bucket)) => (endpoint, bucket),
+                _ => {
+                    return Err(ServerError::Operation(
+                        "The S3 file store requires `--s3-endpoint` and `--s3-bucket`.".to_string(),
+                    ))
+                }
+            };
+
+            Box::new(S3Store::new(
+                endpoint,
+                bucket,
+                &config.s3_region,
+                &config.root,
+            )?)
+        }
+    };
+
+    FILE_STORE
+        .set(file_store)
+        .map_err(|_| ServerError::Operation("Failed to set `FILE_STORE`.".to_string()))
+}
+
+/// The root of the working copy.
+pub(crate) fn working_root() -> &'static Path {
+    Path::new(WORKING_COPY)
+}
+
+/// The directory of the given file id in the working copy.
+pub(crate) fn working_dir(file_id: &str) -> PathBuf {
+    working_root().join(file_id)
+}
+
+/// The path of the given file in the working copy.
+pub(crate) fn working_path(file_id: &str, filename: &str) -> PathBuf {
+    working_dir(file_id).join(filename)
+}
+
+/// Whether the store is apart from the working copy, i.e. whether a file missing from the
+/// working copy may still be in the store.
+pub(crate) fn is_remote() -> bool {
+    !store().is_working_copy()
+}
+
+/// Copy a file of the working copy to the store.
+pub(crate) async fn save(file_id: &str, filename: &str) -> Result<(), ServerError> {
+    if !is_remote() {
+        return Ok(());
+    }
+
+    store()
+        .put_file(
+            &format!("{}/{}", file_id, filename),
+            &working_path(file_id, filename),
+        )
+        .await
+}
+
+/// Delete a single file from the store. The working copy is left to the caller.
+pub(crate) async fn delete(file_id: &str, filename: &str) -> Result<(), ServerError> {
+    if !is_remote() {
+        return Ok(());
+    }
+
+    store().delete(&format!("{}/{}", file_id, filename)).await
+}
+
+/// Make sure the files of the given id are in the working copy, fetching them from the store if
+/// needed. Returns the names of the stored files, or an empty list if the store has none.
+pub(crate) async fn fetch(file_id: &str) -> Result<Vec<String>, ServerError> {
+    if !is_file_id(file_id) {
+        return Ok(Vec::new());
+    }
+
+    let keys = store().list(&format!("{}/", file_id)).await?;
+
+    let dir = working_dir(file_id);
+    let mut filenames = Vec::new();
+    for key in keys {
+        let filename = match key.rsplit_once('/') {
+            Some((_, filename)) if !filename.is_empty() => filename.to_string(),
+            _ => continue,
+        };
+
+        let path = dir.join(&filename);
+        if !path.is_file() {
+            fs::create_dir_all(&dir).map_err(|e| {
+                ServerError::Operation(format!("Failed to create the archive directory. {}", e))
+            })?;
+            if !store().get_file(&key, &path).await? {
+                continue;
+            }
+
+            info!(target: "stdout", "Fetched {} from the file store", &key);
+        }
+
+        filenames.push(filename);
+    }
+
+    Ok(filenames)
+}
+
+/// Whether the store has files of the given id.
+pub(crate) async fn has_files(file_id: &str) -> Result<bool, ServerError> {
+    if !is_file_id(file_id) {
+        return Ok(false);
+    }
+
+    Ok(!store().list(&format!("{}/", file_id)).await?.is_empty())
+}
+
+/// Delete the files of the given id from the store. The working copy is left to the caller.
+pub(crate) async fn remove(file_id: &str) -> Result<(), ServerError> {
+    if file_id.is_empty() || !is_remote() {
+        return Ok(());
+    }
+
+    for key in store().list(&format!("{}/", file_id)).await? {
+        store().delete(&key).await?;
+    }
+
+    Ok(())
+}
+
+/// Delete the files of the given id from the store in the background, from a sync context.
+pub(crate) fn remove_later(file_id: impl Into<String>) {
+    if !is_remote() {
+        return;
+    }
+
+    let file_id = file_id.into();
+    tokio::spawn(async move {
+        if let Err(e) = remove(&file_id).await {
+            error!(target: "stdout", "Failed to delete {} from the file store. {}", &file_id, e);
+        }
+    });
+}
+
+/// Store the serialized record of the given file, next to the files of the store, so that the
+/// instances sharing the store see each other's files.
+pub(crate) async fn save_record(file_id: &str, data: Vec<u8>) -> Result<(), ServerError> {
+    if !is_remote() || !is_file_id(file_id) {
+        return Ok(());
+    }
+
+    store().put(&record_key(file_id), data).await
+}
+
+/// The serialized record of the given file, or `None` if the store has none.
+pub(crate) async fn record(file_id: &str) -> Result<Option<Vec<u8>>, ServerError> {
+    if !is_remote() || !is_file_id(file_id) {
+        return Ok(None);
+    }
+
+    store().get(&record_key(file_id)).await
+}
+
+/// The ids of the files recorded in the store.
+pub(crate) async fn record_ids() -> Result<Vec<String>, ServerError> {
+    if !is_remote() {
+        return Ok(Vec::new());
+    }
+
+    let keys = store().list(RECORD_PREFIX).await?;
+
+    Ok(keys
+        .iter()
+        .filter_map(|key| key.strip_prefix(RECORD_PREFIX)?.strip_suffix(".json"))
+        .map(|file_id| file_id.to_string())
+        .collect())
+}
+
+/// Delete the record of the given file from the store in the background, from a sync context.
+pub(crate) fn remove_record_later(file_id: impl Into<String>) {
+    if !is_remote() {
+        return;
+    }
+
+    let file_id = file_id.into();
+    tokio::spawn(async move {
+        if let Err(e) = store().delete(&record_key(&file_id)).await {
+            error!(target: "stdout", "Failed to delete the record of {} from the file store. {}", &file_id, e);
+        }
+    });
+}
+
+fn store() -> &'static dyn FileStore {
+    FILE_STORE
+        .get_or_init(|| Box::new(LocalStore::new(WORKING_COPY)))
+        .as_ref()
+}
+
+fn record_key(file_id: &str) -> String {
+    format!("{}{}.json", RECORD_PREFIX, file_id)
+}
+
+// whether the string may be a file id, which is also a directory name of the working copy
+fn is_file_id(file_id: &str) -> bool {
+    !file_id.is_empty() && !file_id.contains(['/', '\\', '.'])
+}
+
+// whether two paths name the same directory, e.g. `archives`, `./archives` and `archives/`;
+// relative paths are taken from the current directory
+fn same_dir(a: &Path, b: &Path) -> bool {
+    let normalize = |path: &Path| -> PathBuf {
+        let path = match path.is_absolute() {
+            true => path.to_path_buf(),
+            false => std::env::current_dir()
+                .map(|dir| dir.join(path))
+                .unwrap_or_else(|_| path.to_path_buf()),
+        };
+
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => (),
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                component => normalized.push(component),
+            }
+        }
+
+        normalized
+    };
+
+    normalize(a) == normalize(b)
+}
+
+// percent-encode a uri component as required by the signature version 4
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// `YYYYMMDD` and `YYYYMMDDTHHMMSSZ` of the given time, in UTC
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|n| n.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // civil date of the days since 1970-01-01
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    );
+
+    (date, timestamp)
+}
+
+// the unescaped text of the elements with the given tag
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let end = match rest.find(&close) {
+            Some(end) => end,
+            None => break,
+        };
+        values.push(
+            rest[..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = &rest[end + close.len()..];
+    }
+
+    values
+}
+
+fn s3_error(op: &str, key: &str, status: StatusCode, body: &[u8]) -> ServerError {
+    let message = xml_values(&String::from_utf8_lossy(body), "Message")
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+
+    ServerError::Operation(format!(
+        "Failed to {} {} in the S3 file store. {} {}",
+        op, key, status, message
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("store_{}_{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[tokio::test]
+    async fn memory_store_round_trip() {
+        let store = MemoryStore::default();
+
+        store.put("file_b/audio.wav", b"b".to_vec()).await.unwrap();
+        store.put("file_a/audio.wav", b"a".to_vec()).await.unwrap();
+        store
+            .put("file_a/transcript.json", b"{}".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get("file_a/audio.wav").await.unwrap(),
+            Some(b"a".to_vec())
+        );
+        assert_eq!(store.get("file_c/audio.wav").await.unwrap(), None);
+        assert_eq!(
+            store.list("file_a/").await.unwrap(),
+            vec!["file_a/audio.wav", "file_a/transcript.json"]
+        );
+
+        store.delete("file_a/audio.wav").await.unwrap();
+        store.delete("file_a/audio.wav").await.unwrap();
+        assert_eq!(store.get("file_a/audio.wav").await.unwrap(), None);
+        assert_eq!(
+            store.list("file_a/").await.unwrap(),
+            vec!["file_a/transcript.json"]
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_store_files() {
+        let store = MemoryStore::default();
+        let dir = temp_dir("memory");
+
+        let source = dir.join("source.wav");
+        fs::write(&source, b"RIFF").unwrap();
+        store.put_file("file_a/audio.wav", &source).await.unwrap();
+        assert_eq!(
+            store.get("file_a/audio.wav").await.unwrap(),
+            Some(b"RIFF".to_vec())
+        );
+
+        let target = dir.join("target.wav");
+        assert!(store.get_file("file_a/audio.wav", &target).await.unwrap());
+        assert_eq!(fs::read(&target).unwrap(), b"RIFF");
+
+        let missing = dir.join("missing.wav");
+        assert!(!store.get_file("file_b/audio.wav", &missing).await.unwrap());
+        assert!(!missing.exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn records_are_apart_from_files() {
+        let store = MemoryStore::default();
+
+        store.put("file_a/audio.wav", b"a".to_vec()).await.unwrap();
+        store
+            .put(&record_key("file_a"), b"{}".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(record_key("file_a"), ".index/file_a.json");
+        assert_eq!(
+            store.list("file_a/").await.unwrap(),
+            vec!["file_a/audio.wav"]
+        );
+        assert_eq!(
+            store.list(RECORD_PREFIX).await.unwrap(),
+            vec![".index/file_a.json"]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_store_copies_files() {
+        let root = temp_dir("local");
+        let store = LocalStore::new(root.join("store"));
+
+        let source = root.join("source.wav");
+        fs::write(&source, b"RIFF").unwrap();
+        store.put_file("file_a/audio.wav", &source).await.unwrap();
+        assert_eq!(
+            store.list("file_a/").await.unwrap(),
+            vec!["file_a/audio.wav"]
+        );
+
+        let target = root.join("target.wav");
+        assert!(store.get_file("file_a/audio.wav", &target).await.unwrap());
+        assert_eq!(fs::read(&target).unwrap(), b"RIFF");
+        assert!(!store.get_file("file_b/audio.wav", &target).await.unwrap());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn working_copy_is_matched_by_path() {
+        assert!(LocalStore::new("archives").is_working_copy());
+        assert!(LocalStore::new("./archives").is_working_copy());
+        assert!(LocalStore::new("archives/").is_working_copy());
+        assert!(LocalStore::new("data/../archives").is_working_copy());
+        assert!(
+            LocalStore::new(std::env::current_dir().unwrap().join("archives")).is_working_copy()
+        );
+        assert!(!LocalStore::new("data/archives").is_working_copy());
+        assert!(!MemoryStore::default().is_working_copy());
+    }
+
+    #[test]
+    fn file_ids_are_checked() {
+        assert!(is_file_id("file_8a2c"));
+        assert!(!is_file_id(""));
+        assert!(!is_file_id("../file_a"));
+        assert!(!is_file_id(".index"));
+        assert!(!is_file_id("file_a/audio.wav"));
+    }
+
+    #[test]
+    fn amz_date_is_utc() {
+        // 2024-02-29T12:34:56Z
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1709210096);
+
+        assert_eq!(
+            amz_date(time),
+            ("20240229".to_string(), "20240229T123456Z".to_string())
+        );
+    }
+}
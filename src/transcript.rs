@@ -0,0 +1,380 @@
+use crate::vad::SpeechSegment;
+use serde::{Deserialize, Serialize};
+
+/// Output format of the transcription and translation endpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ResponseFormat {
+    /// `{"text": "..."}` without timestamp markers.
+    #[default]
+    Json,
+    /// Plain text.
+    Text,
+    /// SubRip subtitles.
+    Srt,
+    /// `json` plus `language`, `duration` and `segments`.
+    VerboseJson,
+    /// WebVTT subtitles.
+    Vtt,
+}
+impl ResponseFormat {
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            ResponseFormat::Json | ResponseFormat::VerboseJson => "application/json",
+            ResponseFormat::Text => "text/plain; charset=utf-8",
+            ResponseFormat::Srt => "application/x-subrip; charset=utf-8",
+            ResponseFormat::Vtt => "text/vtt; charset=utf-8",
+        }
+    }
+}
+impl std::fmt::Display for ResponseFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ResponseFormat::Json => write!(f, "json"),
+            ResponseFormat::Text => write!(f, "text"),
+            ResponseFormat::Srt => write!(f, "srt"),
+            ResponseFormat::VerboseJson => write!(f, "verbose_json"),
+            ResponseFormat::Vtt => write!(f, "vtt"),
+        }
+    }
+}
+impl std::str::FromStr for ResponseFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "json" => Ok(ResponseFormat::Json),
+            "text" => Ok(ResponseFormat::Text),
+            "srt" => Ok(ResponseFormat::Srt),
+            "verbose_json" => Ok(ResponseFormat::VerboseJson),
+            "vtt" => Ok(ResponseFormat::Vtt),
+            _ => Err(format!(
+                "Invalid response format: {}. Supported formats are `json`, `text`, `srt`, `verbose_json` and `vtt`.",
+                s
+            )),
+        }
+    }
+}
+
+/// Level of detail of the timestamps in the `verbose_json` response format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimestampGranularity {
+    /// Word-level timestamps.
+    Word,
+    /// Segment-level timestamps.
+    Segment,
+}
+impl std::fmt::Display for TimestampGranularity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TimestampGranularity::Word => write!(f, "word"),
+            TimestampGranularity::Segment => write!(f, "segment"),
+        }
+    }
+}
+impl std::str::FromStr for TimestampGranularity {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "word" => Ok(TimestampGranularity::Word),
+            "segment" => Ok(TimestampGranularity::Segment),
+            _ => Err(format!(
+                "Invalid timestamp granularity: {}. Supported granularities are `word` and `segment`.",
+                s
+            )),
+        }
+    }
+}
+
+/// A segment of the whisper output, with times in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Segment {
+    pub(crate) id: usize,
+    pub(crate) start: f64,
+    pub(crate) end: f64,
+    pub(crate) text: String,
+}
+
+/// A word of the whisper output, with times in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Word {
+    pub(crate) word: String,
+    pub(crate) start: f64,
+    pub(crate) end: f64,
+}
+
+/// Body of the `verbose_json` response format.
+#[derive(Debug, Serialize)]
+pub(crate) struct VerboseTranscript {
+    /// `transcribe` or `translate`.
+    pub(crate) task: String,
+    pub(crate) language: Option<String>,
+    /// Duration of the input audio in seconds.
+    pub(crate) duration: f64,
+    pub(crate) text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) segments: Option<Vec<Segment>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) words: Option<Vec<Word>>,
+    /// Speech regions found by the voice activity detector, if enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) speech_segments: Option<Vec<SpeechSegment>>,
+}
+
+/// Parse the whisper output, in which each line looks like
+/// `[00:00:00.000 --> 00:00:03.540]  This is a test record`, into segments.
+///
+/// Lines without a timestamp marker are appended to the previous segment.
+pub(crate) fn parse_segments(output: impl AsRef<str>) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for line in output.as_ref().lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match parse_marker(line) {
+            Some((start, end, text)) => segments.push(Segment {
+                id: segments.len(),
+                start,
+                end,
+                text: text.to_string(),
+            }),
+            None => match segments.last_mut() {
+                Some(segment) => {
+                    segment.text.push(' ');
+                    segment.text.push_str(line);
+                }
+                None => segments.push(Segment {
+                    id: 0,
+                    start: 0.0,
+                    end: 0.0,
+                    text: line.to_string(),
+                }),
+            },
+        }
+    }
+
+    segments
+}
+
+// split `[start --> end] text` into its parts
+fn parse_marker(line: &str) -> Option<(f64, f64, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let close = rest.find(']')?;
+    let (start, end) = rest[..close].split_once("-->")?;
+    let start = parse_timestamp(start.trim())?;
+    let end = parse_timestamp(end.trim())?;
+
+    Some((start, end, rest[close + 1..].trim()))
+}
+
+// parse `HH:MM:SS.mmm` into seconds
+fn parse_timestamp(s: &str) -> Option<f64> {
+    let mut parts = s.split(':').rev();
+    let seconds: f64 = parts.next()?.replace(',', ".").parse().ok()?;
+    let minutes: f64 = match parts.next() {
+        Some(minutes) => minutes.parse().ok()?,
+        None => 0.0,
+    };
+    let hours: f64 = match parts.next() {
+        Some(hours) => hours.parse().ok()?,
+        None => 0.0,
+    };
+
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Convert the segments of a word-level whisper run, i.e. with `max_len = 1` and
+/// `split_on_word = true`, in which each segment holds a single word, into words.
+pub(crate) fn words_from_segments(segments: Vec<Segment>) -> Vec<Word> {
+    segments
+        .into_iter()
+        .filter(|segment| !segment.text.is_empty())
+        .map(|segment| Word {
+            word: segment.text,
+            start: segment.start,
+            end: segment.end,
+        })
+        .collect()
+}
+
+/// Group the words of a word-level whisper run into segments, so that a single run gives both.
+///
+/// A segment ends after a word which ends a sentence, or before a pause between two words.
+pub(crate) fn segments_from_words(words: &[Word]) -> Vec<Segment> {
+    // pause between two words which starts a new segment, in seconds
+    const MIN_PAUSE_SECONDS: f64 = 1.0;
+
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut sentence_end = true;
+    for word in words {
+        let id = segments.len();
+        match segments.last_mut() {
+            Some(segment) if !sentence_end && word.start - segment.end < MIN_PAUSE_SECONDS => {
+                if needs_space(&segment.text, &word.word) {
+                    segment.text.push(' ');
+                }
+                segment.text.push_str(&word.word);
+                segment.end = word.end;
+            }
+            _ => segments.push(Segment {
+                id,
+                start: word.start,
+                end: word.end,
+                text: word.word.clone(),
+            }),
+        }
+
+        sentence_end = word.word.ends_with(['.', '?', '!', '。', '？', '！']);
+    }
+
+    segments
+}
+
+// whether two consecutive words are separated by a space: the scripts written without spaces,
+// e.g. Chinese and Japanese, and the punctuation are joined to the previous word
+fn needs_space(previous: &str, next: &str) -> bool {
+    let is_unspaced = |c: char| matches!(c, '\u{3000}'..='\u{30ff}' | '\u{3400}'..='\u{9fff}' | '\u{f900}'..='\u{faff}' | '\u{ff00}'..='\u{ffef}');
+
+    match (previous.chars().last(), next.chars().next()) {
+        (Some(last), Some(first)) => {
+            !(is_unspaced(last)
+                || is_unspaced(first)
+                || matches!(first, ',' | '.' | '!' | '?' | ';' | ':' | ')' | '%'))
+        }
+        _ => false,
+    }
+}
+
+/// Merge the segments of overlapping windows into a single timeline.
+///
+/// Each window is given with its start time, in seconds, and segments whose times are relative
+/// to it. Consecutive windows overlap by `overlap` seconds. A segment is kept by the window in
+/// which its midpoint falls before the middle of the overlap, and the words repeated across
+/// the boundary are dropped from the first segment of the next window.
+pub(crate) fn stitch(windows: Vec<(f64, Vec<Segment>)>, overlap: f64) -> Vec<Segment> {
+    // maximum number of words compared across a window boundary
+    const MAX_REPEATED_WORDS: usize = 8;
+
+    let starts: Vec<f64> = windows.iter().map(|(start, _)| *start).collect();
+    let mut merged: Vec<Segment> = Vec::new();
+
+    for (idx, (start, segments)) in windows.into_iter().enumerate() {
+        let lower = match idx {
+            0 => f64::MIN,
+            _ => start + overlap / 2.0,
+        };
+        let upper = match starts.get(idx + 1) {
+            Some(next_start) => next_start + overlap / 2.0,
+            None => f64::MAX,
+        };
+
+        let mut first = true;
+        for mut segment in segments {
+            segment.start += start;
+            segment.end += start;
+
+            let midpoint = (segment.start + segment.end) / 2.0;
+            if midpoint < lower || midpoint >= upper {
+                continue;
+            }
+
+            if first && idx > 0 {
+                segment.text = drop_repeated_words(&merged, &segment.text, MAX_REPEATED_WORDS);
+            }
+            first = false;
+
+            if !segment.text.is_empty() {
+                segment.id = merged.len();
+                merged.push(segment);
+            }
+        }
+    }
+
+    merged
+}
+
+// remove the leading words of `text` which repeat the trailing words of the merged segments
+fn drop_repeated_words(merged: &[Segment], text: &str, max_words: usize) -> String {
+    let normalize = |word: &str| {
+        word.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(|c| c.to_lowercase())
+            .collect::<String>()
+    };
+
+    let previous: Vec<String> = merged
+        .iter()
+        .rev()
+        .flat_map(|segment| segment.text.split_whitespace().rev())
+        .take(max_words)
+        .map(normalize)
+        .collect::<Vec<String>>()
+        .into_iter()
+        .rev()
+        .collect();
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let current: Vec<String> = words.iter().map(|word| normalize(word)).collect();
+
+    let longest = (1..=previous.len().min(current.len()))
+        .rev()
+        .find(|&n| previous[previous.len() - n..] == current[..n])
+        .unwrap_or(0);
+
+    words[longest..].join(" ")
+}
+
+/// Join the text of the segments without timestamp markers.
+pub(crate) fn plain_text(segments: &[Segment]) -> String {
+    segments
+        .iter()
+        .map(|segment| segment.text.as_str())
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Render the segments as SubRip subtitles.
+pub(crate) fn to_srt(segments: &[Segment]) -> String {
+    let mut srt = String::new();
+    for (idx, segment) in segments.iter().enumerate() {
+        srt.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            idx + 1,
+            format_timestamp(segment.start, ','),
+            format_timestamp(segment.end, ','),
+            segment.text
+        ));
+    }
+
+    srt
+}
+
+/// Render the segments as WebVTT subtitles.
+pub(crate) fn to_vtt(segments: &[Segment]) -> String {
+    let mut vtt = String::from("WEBVTT\n\n");
+    for segment in segments {
+        vtt.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            format_timestamp(segment.start, '.'),
+            format_timestamp(segment.end, '.'),
+            segment.text
+        ));
+    }
+
+    vtt
+}
+
+// format seconds as `HH:MM:SS<sep>mmm`
+fn format_timestamp(seconds: f64, decimal_separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        decimal_separator,
+        millis % 1000
+    )
+}
@@ -0,0 +1,75 @@
+use crate::error;
+use hyper::{Body, Response, StatusCode};
+use std::{collections::HashMap, time::SystemTime};
+
+/// Parse the query string of a request uri into a map. The keys and values are percent-decoded.
+pub(crate) fn query_params(query: Option<&str>) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+
+    if let Some(query) = query {
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            params.insert(percent_decode(key), percent_decode(value));
+        }
+    }
+
+    params
+}
+
+// decode `%XX` escapes and `+` in a query component
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Current Unix time, in seconds.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|n| n.as_secs())
+        .unwrap_or_default()
+}
+
+/// Build a JSON response with the given status and the CORS headers of the server.
+pub(crate) fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    let result = Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(body));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{audio::WavAudio, error::ServerError, transcript::Segment, transcript::Word, VAD};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// length of the analysis frames of the energy detector, in seconds
+const FRAME_SECONDS: f64 = 0.03;
+// speech regions shorter than this are treated as clicks, in seconds
+const MIN_SPEECH_SECONDS: f64 = 0.09;
+// silence kept around each speech region, in seconds
+const PADDING_SECONDS: f64 = 0.2;
+
+/// Voice activity detector used to skip the silences of the input audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum VadKind {
+    /// Do not detect voice activity.
+    #[value(name = "none")]
+    Off,
+    /// Detect speech from the short-term energy and the zero-crossing rate of the audio.
+    #[value(name = "energy")]
+    Energy,
+}
+impl VadKind {
+    /// The detector of this kind, or `None` for [`VadKind::Off`].
+    pub(crate) fn detector(&self) -> Option<Box<dyn VoiceActivityDetector>> {
+        match self {
+            VadKind::Off => None,
+            VadKind::Energy => Some(Box::new(EnergyDetector::default())),
+        }
+    }
+
+    /// Resolve the `vad` field of a request against the default set from the command line.
+    ///
+    /// The field is either a detector name, or `true` / `false` to switch the default detector
+    /// on or off.
+    pub(crate) fn for_request(value: Option<&str>) -> Result<Self, String> {
+        let default = VAD.get().map(|vad| vad.kind).unwrap_or(VadKind::Off);
+
+        match value.map(|value| value.trim().to_lowercase()).as_deref() {
+            None => Ok(default),
+            Some("true") => match default {
+                VadKind::Off => Ok(VadKind::Energy),
+                kind => Ok(kind),
+            },
+            Some("false") => Ok(VadKind::Off),
+            Some(value) => VadKind::from_str(value, true).map_err(|_| {
+                format!(
+                    "Invalid vad: {}. Supported values are `true`, `false`, `none` and `energy`.",
+                    value
+                )
+            }),
+        }
+    }
+}
+impl std::fmt::Display for VadKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VadKind::Off => write!(f, "none"),
+            VadKind::Energy => write!(f, "energy"),
+        }
+    }
+}
+
+/// Voice activity detection settings set from the command line.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VadConfig {
+    pub(crate) kind: VadKind,
+    /// Silences shorter than this are kept, in seconds.
+    pub(crate) min_silence: f64,
+}
+
+/// A region of the input audio which contains speech, with times in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SpeechSegment {
+    pub(crate) start: f64,
+    pub(crate) end: f64,
+}
+
+/// Find the regions of an audio which contain speech.
+///
+/// Implementations return raw regions, in order and without overlap. Padding the regions and
+/// merging those separated by short silences is left to the caller.
+pub(crate) trait VoiceActivityDetector: Send + Sync {
+    fn detect(&self, audio: &WavAudio) -> Vec<SpeechSegment>;
+}
+
+/// Frame-based detector on the short-term energy and the zero-crossing rate.
+///
+/// The energy threshold adapts to the noise floor of the audio. Frames with a lower energy but a
+/// high zero-crossing rate, such as fricatives, are also counted as speech.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EnergyDetector {
+    /// Ratio of the energy threshold to the noise floor.
+    pub(crate) energy_ratio: f32,
+    /// Frames quieter than this RMS level are always silent.
+    pub(crate) min_energy: f32,
+    /// Zero-crossing rate above which a frame with half the energy threshold is speech.
+    pub(crate) zcr_threshold: f32,
+}
+impl Default for EnergyDetector {
+    fn default() -> Self {
+        Self {
+            energy_ratio: 3.0,
+            min_energy: 0.001,
+            zcr_threshold: 0.25,
+        }
+    }
+}
+impl VoiceActivityDetector for EnergyDetector {
+    fn detect(&self, audio: &WavAudio) -> Vec<SpeechSegment> {
+        let sample_rate = audio.spec.sample_rate as f64;
+        let channels = audio.spec.channels.max(1) as usize;
+        let frame_len = ((FRAME_SECONDS * sample_rate) as usize).max(1);
+
+        // mix down to mono
+        let mono: Vec<f32> = audio
+            .samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+            .collect();
+
+        let frames: Vec<(f32, f32)> = mono
+            .chunks(frame_len)
+            .map(|frame| {
+                let rms = (frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32).sqrt();
+                let crossings = frame
+                    .windows(2)
+                    .filter(|pair| (pair[0] >= 0.0) != (pair[1] >= 0.0))
+                    .count();
+                let zcr = crossings as f32 / frame.len().saturating_sub(1).max(1) as f32;
+
+                (rms, zcr)
+            })
+            .collect();
+        if frames.is_empty() {
+            return Vec::new();
+        }
+
+        let mut energies: Vec<f32> = frames.iter().map(|(rms, _)| *rms).collect();
+        energies.sort_by(|a, b| a.total_cmp(b));
+        let floor = energies[energies.len() / 10];
+        let peak = energies[energies.len() * 95 / 100];
+        let threshold = (floor * self.energy_ratio)
+            .min(peak * 0.5)
+            .max(self.min_energy);
+
+        let mut regions: Vec<SpeechSegment> = Vec::new();
+        let mut start: Option<usize> = None;
+        for (idx, (rms, zcr)) in frames.iter().enumerate() {
+            let speech = *rms >= threshold
+                || (*rms >= threshold / 2.0
+                    && *rms >= self.min_energy
+                    && *zcr >= self.zcr_threshold);
+
+            match (speech, start) {
+                (true, None) => start = Some(idx),
+                (false, Some(first)) => {
+                    regions.push(SpeechSegment {
+                        start: (first * frame_len) as f64 / sample_rate,
+                        end: (idx * frame_len) as f64 / sample_rate,
+                    });
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(first) = start {
+            regions.push(SpeechSegment {
+                start: (first * frame_len) as f64 / sample_rate,
+                end: mono.len() as f64 / sample_rate,
+            });
+        }
+
+        regions
+    }
+}
+
+/// Middle of the longest silence between two speech regions of an audio which starts after
+/// `after` seconds, or `None` if there is no such silence. Used to cut long audio into windows
+/// without splitting a word.
+pub(crate) fn longest_silence(audio: &WavAudio, after: f64) -> Option<f64> {
+    let regions = EnergyDetector::default().detect(audio);
+
+    regions
+        .windows(2)
+        .map(|pair| (pair[0].end, pair[1].start))
+        .filter(|(start, _)| *start >= after)
+        .max_by(|a, b| (a.1 - a.0).total_cmp(&(b.1 - b.0)))
+        .map(|(start, end)| (start + end) / 2.0)
+}
+
+/// Speech detected in an audio file of the archive directory, and the copy of the file without
+/// its long silences.
+///
+/// The copy is removed when the value is dropped.
+pub(crate) struct Speech {
+    /// Speech regions, in the timeline of the original audio.
+    pub(crate) segments: Vec<SpeechSegment>,
+    // wav file holding only the speech regions, if any silence was skipped
+    file: Option<PathBuf>,
+}
+impl Speech {
+    /// Detect the speech of the wav file at the given path and, if it contains silences longer
+    /// than `min_silence` seconds, save the audio without them next to it.
+    ///
+    /// Only the speech in `range`, the start and end in seconds of the requested part of the
+    /// audio, is kept. The end is `None` for the rest of the audio.
+    pub(crate) fn detect(
+        path: impl AsRef<Path>,
+        detector: &dyn VoiceActivityDetector,
+        min_silence: f64,
+        range: (f64, Option<f64>),
+    ) -> Result<Self, ServerError> {
+        let path = path.as_ref();
+        let audio = WavAudio::open(path)?;
+        let duration = audio.duration();
+
+        // drop clicks, pad the regions and merge those separated by short silences
+        let mut segments: Vec<SpeechSegment> = Vec::new();
+        for region in detector.detect(&audio) {
+            if region.end - region.start < MIN_SPEECH_SECONDS {
+                continue;
+            }
+
+            let start = (region.start - PADDING_SECONDS).max(0.0);
+            let end = (region.end + PADDING_SECONDS).min(duration);
+            match segments.last_mut() {
+                Some(last) if start - last.end < min_silence => last.end = end,
+                _ => segments.push(SpeechSegment { start, end }),
+            }
+        }
+
+        // keep the requested range of the audio
+        let (range_start, range_end) = (range.0, range.1.map_or(duration, |end| end.min(duration)));
+        let segments: Vec<SpeechSegment> = segments
+            .into_iter()
+            .map(|segment| SpeechSegment {
+                start: segment.start.max(range_start),
+                end: segment.end.min(range_end),
+            })
+            .filter(|segment| segment.end > segment.start)
+            .collect();
+
+        let speech: f64 = segments
+            .iter()
+            .map(|segment| segment.end - segment.start)
+            .sum();
+        if segments.is_empty() || speech >= duration {
+            return Ok(Self {
+                segments,
+                file: None,
+            });
+        }
+
+        let mut samples = Vec::new();
+        for segment in segments.iter() {
+            samples.extend(audio.slice(segment.start, segment.end).samples);
+        }
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file = path.with_file_name(format!("{}.speech.wav", stem));
+        WavAudio {
+            spec: audio.spec,
+            samples,
+        }
+        .save(&file)?;
+
+        info!(target: "stdout", "Skip {:.3}s of silence in {}", duration - speech, path.display());
+
+        Ok(Self {
+            segments,
+            file: Some(file),
+        })
+    }
+
+    /// No speech was detected.
+    pub(crate) fn is_silent(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Name of the file holding only the speech, if any silence was skipped.
+    pub(crate) fn filename(&self) -> Option<String> {
+        self.file
+            .as_ref()
+            .and_then(|file| file.file_name())
+            .map(|filename| filename.to_string_lossy().to_string())
+    }
+
+    /// Map a time of the audio without silences back to the original audio.
+    pub(crate) fn to_original(&self, time: f64) -> f64 {
+        if self.file.is_none() {
+            return time;
+        }
+
+        let mut offset = 0.0;
+        for segment in self.segments.iter() {
+            let length = segment.end - segment.start;
+            if time < offset + length {
+                return segment.start + (time - offset).max(0.0);
+            }
+            offset += length;
+        }
+
+        match self.segments.last() {
+            Some(segment) => segment.end + (time - offset),
+            None => time,
+        }
+    }
+
+    /// Map the times of the segments back to the original audio.
+    pub(crate) fn remap_segments(&self, segments: Vec<Segment>) -> Vec<Segment> {
+        segments
+            .into_iter()
+            .map(|mut segment| {
+                segment.start = self.to_original(segment.start);
+                segment.end = self.to_original(segment.end);
+                segment
+            })
+            .collect()
+    }
+
+    /// Map the times of the words back to the original audio.
+    pub(crate) fn remap_words(&self, words: Vec<Word>) -> Vec<Word> {
+        words
+            .into_iter()
+            .map(|mut word| {
+                word.start = self.to_original(word.start);
+                word.end = self.to_original(word.end);
+                word
+            })
+            .collect()
+    }
+}
+impl Drop for Speech {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = fs::remove_file(file);
+        }
+    }
+}